@@ -0,0 +1,223 @@
+//! HTTP API for operators, enabled by setting `admin.token`. Requests have to carry it as
+//! `Authorization: Bearer <token>`.
+use crate::auth::is_token;
+use crate::role::Role;
+use crate::webrtc::PeerStats;
+use crate::{Groups, Result, ServerOptions};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use warp::http::StatusCode;
+use warp::Reply;
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    groups: Vec<GroupStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct GroupStats {
+    id: String,
+    recording: bool,
+    clients: Vec<ClientStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct RecordingStatus {
+    group: String,
+    recording: bool,
+    /// Files of the tracks being recorded.
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayRequest {
+    /// File name of the dump in the RTP dump directory.
+    dump: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayStatus {
+    group: String,
+    /// Stream id the replayed tracks are published with.
+    stream_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ClientStats {
+    client_id: String,
+    role: Role,
+    /// Bandwidth estimate and forwarded layers, unset until the client negotiated.
+    peer_connection: Option<PeerStats>,
+}
+
+/// The response rejecting the request, if it doesn't carry the admin token.
+fn reject(authorization: Option<&str>, options: &ServerOptions) -> Option<warp::reply::Response> {
+    let token = match &options.admin_token {
+        Some(token) => token,
+        None => return Some(StatusCode::NOT_FOUND.into_response()),
+    };
+    match authorization.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(presented) if is_token(presented, token) => None,
+        _ => {
+            warn!("Rejecting admin request with invalid token");
+            Some(
+                warp::reply::with_status("invalid token", StatusCode::UNAUTHORIZED).into_response(),
+            )
+        }
+    }
+}
+
+/// `GET /admin/stats`: the clients of every group with the bandwidth estimated towards them and
+/// the layers picked for each of their subscriptions.
+pub async fn stats_handler(
+    authorization: Option<String>,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    if let Some(rejection) = reject(authorization.as_deref(), &options) {
+        return Ok(rejection);
+    }
+
+    let mut groups: Vec<_> = groups
+        .lock()
+        .await
+        .iter()
+        .map(|(id, group)| (id.clone(), group.clone()))
+        .collect();
+    groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut stats = Stats { groups: Vec::new() };
+    for (id, group) in groups {
+        let (clients, recording) = {
+            let group = group.lock().await;
+            (group.clients.clone(), group.recorder.is_some())
+        };
+        let clients = clients.lock().await.clone();
+        let mut client_stats = Vec::with_capacity(clients.len());
+        for client in clients {
+            let client = client.lock().await;
+            let peer_connection = match &client.peer_connection {
+                Some(pc) => Some(pc.stats().await),
+                None => None,
+            };
+            client_stats.push(ClientStats {
+                client_id: client.client_id.clone(),
+                role: client.role,
+                peer_connection,
+            });
+        }
+        stats.groups.push(GroupStats {
+            id,
+            recording,
+            clients: client_stats,
+        });
+    }
+    Ok(warp::reply::json(&stats).into_response())
+}
+
+/// `POST /admin/groups/<id>/recording` starts recording group `id`, `DELETE` stops it. Both
+/// answer with the recording status of the group and the files being written, or just finished.
+pub async fn recording_handler(
+    group_id: String,
+    recording: bool,
+    authorization: Option<String>,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    if let Some(rejection) = reject(authorization.as_deref(), &options) {
+        return Ok(rejection);
+    }
+    let directory = match &options.recording_directory {
+        Some(directory) => directory,
+        None => {
+            return Ok(
+                warp::reply::with_status("recording is disabled", StatusCode::CONFLICT)
+                    .into_response(),
+            )
+        }
+    };
+    let group = match groups.lock().await.get(&group_id) {
+        Some(group) => group.clone(),
+        None => {
+            return Ok(
+                warp::reply::with_status("no such group", StatusCode::NOT_FOUND).into_response(),
+            )
+        }
+    };
+    let mut group = group.lock().await;
+    let mut files = match &group.recorder {
+        Some(recorder) => recorder.files(),
+        None => vec![],
+    };
+    let changed = if recording {
+        group.start_recording(&group_id, directory).await
+    } else {
+        group.stop_recording().await
+    };
+    if changed {
+        info!(
+            "Admin {} recording group {}",
+            if recording { "started" } else { "stopped" },
+            group_id
+        );
+    }
+    if let Some(recorder) = &group.recorder {
+        files = recorder.files();
+    }
+    let status = RecordingStatus {
+        group: group_id,
+        recording: group.recorder.is_some(),
+        files,
+    };
+    Ok(warp::reply::json(&status).into_response())
+}
+
+/// `POST /admin/groups/<id>/replay` publishes an RTP dump into group `id`, see `replay`.
+pub async fn replay_handler(
+    group_id: String,
+    request: ReplayRequest,
+    authorization: Option<String>,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    if let Some(rejection) = reject(authorization.as_deref(), &options) {
+        return Ok(rejection);
+    }
+    let directory = match &options.rtp_dump_directory {
+        Some(directory) => directory,
+        None => {
+            return Ok(
+                warp::reply::with_status("RTP dumps are disabled", StatusCode::CONFLICT)
+                    .into_response(),
+            )
+        }
+    };
+    // Only dumps of the directory can be replayed, not any file of the server.
+    if Path::new(&request.dump).file_name() != Some(request.dump.as_ref()) {
+        return Ok(
+            warp::reply::with_status("invalid dump name", StatusCode::BAD_REQUEST).into_response(),
+        );
+    }
+    let group = match groups.lock().await.get(&group_id) {
+        Some(group) => group.clone(),
+        None => {
+            return Ok(
+                warp::reply::with_status("no such group", StatusCode::NOT_FOUND).into_response(),
+            )
+        }
+    };
+    match crate::replay::replay(&group, &directory.join(&request.dump)).await {
+        Ok(stream_id) => {
+            info!("Admin replaying {} into group {}", request.dump, group_id);
+            let status = ReplayStatus {
+                group: group_id,
+                stream_id,
+            };
+            Ok(warp::reply::json(&status).into_response())
+        }
+        Err(err) => Ok(
+            warp::reply::with_status(format!("{:#}", err), StatusCode::BAD_REQUEST).into_response(),
+        ),
+    }
+}
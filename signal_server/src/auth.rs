@@ -0,0 +1,75 @@
+//! Verification of the tokens clients present to join groups.
+//!
+//! Tokens are HS256 signed JWTs carrying the identity of the client (`sub`), the groups it may
+//! join (`rooms`, `"*"` allows any group) and an expiry (`exp`). An optional `role` pins the role
+//! the client plays in those groups.
+use crate::role::Role;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub rooms: Vec<String>,
+    pub exp: u64,
+    pub role: Option<Role>,
+}
+
+impl Claims {
+    pub fn allows_group(&self, group: &str) -> bool {
+        self.rooms.iter().any(|room| room == "*" || room == group)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.exp < jsonwebtoken::get_current_timestamp()
+    }
+}
+
+#[derive(Clone)]
+pub struct Authenticator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator").finish_non_exhaustive()
+    }
+}
+
+impl Authenticator {
+    pub fn new(secret: &str) -> Authenticator {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        Authenticator {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+        decode::<Claims>(token, &self.key, &self.validation).map(|data| data.claims)
+    }
+}
+
+/// Compares bearer secrets in constant time, so that the time taken doesn't tell how much of the
+/// secret was guessed right.
+pub fn is_token(presented: &str, token: &str) -> bool {
+    presented.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(is_token("sekrit", "sekrit"));
+        assert!(!is_token("sekriT", "sekrit"));
+        assert!(!is_token("sekri", "sekrit"));
+        assert!(!is_token("sekrit2", "sekrit"));
+        assert!(!is_token("", "sekrit"));
+    }
+}
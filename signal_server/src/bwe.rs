@@ -0,0 +1,443 @@
+//! Estimates the bandwidth towards a subscriber from the RTCP it sends back, and picks the
+//! simulcast layers forwarded to it accordingly.
+//!
+//! REMB carries the receiver's own estimate and caps ours. Receiver reports and TWCC feedback
+//! carry packet loss, which drives a loss based controller along the lines of GCC: the estimate
+//! grows while loss stays low and shrinks in proportion to it once it gets high.
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use webrtc::rtcp::packet::Packet;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+    PacketStatusChunk, SymbolTypeTcc, TransportLayerCc,
+};
+
+/// How often the estimate is updated and the layers are picked again.
+pub const ALLOCATION_INTERVAL: Duration = Duration::from_secs(1);
+
+const INITIAL_BITRATE: u64 = 1_000_000;
+pub const MIN_BITRATE: u64 = 100_000;
+const MAX_BITRATE: u64 = 20_000_000;
+/// Loss below which the estimate grows, and above which it shrinks.
+const LOW_LOSS: f64 = 0.02;
+const HIGH_LOSS: f64 = 0.10;
+/// REMB values older than this are ignored.
+const REMB_TIMEOUT: Duration = Duration::from_secs(5);
+/// A higher layer is only switched to once it fits with this much to spare...
+const UPGRADE_HEADROOM: f64 = 1.25;
+/// ...for this long, so that the layer doesn't flap with every update of the estimate.
+const UPGRADE_HOLD: Duration = Duration::from_secs(4);
+
+#[derive(Debug, Default)]
+pub struct BandwidthEstimator {
+    /// Driven by loss, unset until the subscriber reported any.
+    loss_based: Option<u64>,
+    remb: Option<(u64, Instant)>,
+    loss_sum: f64,
+    loss_samples: u32,
+    estimate: Estimate,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Estimate {
+    /// Bits per second, unknown until the subscriber sent feedback.
+    pub bitrate: Option<u64>,
+    pub source: EstimateSource,
+    /// Average fraction of packets lost over the last interval.
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EstimateSource {
+    #[default]
+    None,
+    Loss,
+    Remb,
+}
+
+impl BandwidthEstimator {
+    /// Takes the feedback out of RTCP sent by the subscriber.
+    pub fn on_rtcp(&mut self, packets: &[Box<dyn Packet + Send + Sync>]) {
+        for packet in packets {
+            let packet = packet.as_any();
+            if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                self.remb = Some((remb.bitrate as u64, Instant::now()));
+            } else if let Some(report) = packet.downcast_ref::<ReceiverReport>() {
+                for report in &report.reports {
+                    self.on_loss(f64::from(report.fraction_lost) / 256.0);
+                }
+            } else if let Some(feedback) = packet.downcast_ref::<TransportLayerCc>() {
+                if let Some(loss) = twcc_loss(feedback) {
+                    self.on_loss(loss);
+                }
+            }
+        }
+    }
+
+    fn on_loss(&mut self, loss: f64) {
+        self.loss_sum += loss;
+        self.loss_samples += 1;
+    }
+
+    /// Applies the feedback received since the last update.
+    pub fn update(&mut self) -> Estimate {
+        let mut loss = self.estimate.loss;
+        if self.loss_samples > 0 {
+            loss = self.loss_sum / f64::from(self.loss_samples);
+            let current = self.loss_based.unwrap_or(INITIAL_BITRATE) as f64;
+            let next = if loss > HIGH_LOSS {
+                current * (1.0 - 0.5 * loss)
+            } else if loss < LOW_LOSS {
+                current * 1.08
+            } else {
+                current
+            };
+            self.loss_based = Some((next as u64).clamp(MIN_BITRATE, MAX_BITRATE));
+            self.loss_sum = 0.0;
+            self.loss_samples = 0;
+        }
+        let remb = self
+            .remb
+            .filter(|(_, received)| received.elapsed() < REMB_TIMEOUT)
+            .map(|(bitrate, _)| bitrate);
+        let (bitrate, source) = match (self.loss_based, remb) {
+            (Some(loss_based), Some(remb)) if remb < loss_based => {
+                (Some(remb), EstimateSource::Remb)
+            }
+            (Some(loss_based), _) => (Some(loss_based), EstimateSource::Loss),
+            (None, Some(remb)) => (Some(remb), EstimateSource::Remb),
+            (None, None) => (None, EstimateSource::None),
+        };
+        self.estimate = Estimate {
+            bitrate,
+            source,
+            loss,
+        };
+        self.estimate.clone()
+    }
+
+    pub fn estimate(&self) -> &Estimate {
+        &self.estimate
+    }
+}
+
+/// Fraction of the packets a TWCC feedback reports as lost.
+fn twcc_loss(feedback: &TransportLayerCc) -> Option<f64> {
+    // The last chunk may be padded beyond the packets it reports on, with symbols reading as
+    // lost, so only the first `packet_status_count` symbols are looked at.
+    let mut remaining = u32::from(feedback.packet_status_count);
+    let mut total = 0u32;
+    let mut lost = 0u32;
+    for chunk in &feedback.packet_chunks {
+        let (count, missing) = match chunk {
+            PacketStatusChunk::RunLengthChunk(chunk) => {
+                let count = u32::from(chunk.run_length).min(remaining);
+                match chunk.packet_status_symbol {
+                    SymbolTypeTcc::PacketNotReceived => (count, count),
+                    _ => (count, 0),
+                }
+            }
+            PacketStatusChunk::StatusVectorChunk(chunk) => {
+                let symbols = chunk.symbol_list.iter().take(remaining as usize);
+                let count = symbols.len() as u32;
+                let missing = symbols
+                    .filter(|symbol| **symbol == SymbolTypeTcc::PacketNotReceived)
+                    .count() as u32;
+                (count, missing)
+            }
+        };
+        remaining -= count;
+        total += count;
+        lost += missing;
+    }
+    if total == 0 {
+        return None;
+    }
+    Some(f64::from(lost) / f64::from(total))
+}
+
+/// Why a layer was picked for a subscriber.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayerReason {
+    /// Nothing is known about the bandwidth yet, the best layer is sent.
+    #[default]
+    NoEstimate,
+    /// The subscriber picked the layer with `select-layer`.
+    Requested,
+    /// The best layer fitting the share of the estimate.
+    FitsEstimate,
+    /// A better layer fits, but not long enough yet to switch to it.
+    UpgradePending,
+    /// Not even the lowest layer fits, it is sent anyway.
+    Congested,
+}
+
+/// Layer picked for one subscriber of a simulcast track, kept between allocations.
+#[derive(Debug, Clone, Default)]
+pub struct LayerAllocation {
+    pub rid: Option<String>,
+    pub reason: LayerReason,
+    /// Since when a better layer fits with headroom.
+    upgrade_since: Option<Instant>,
+}
+
+impl LayerAllocation {
+    /// Picks one of `layers`, rids with their bitrate sorted from lowest to highest, for a
+    /// subscriber with `share` bits per second available to this track.
+    pub fn allocate(&mut self, layers: &[(String, u64)], share: Option<u64>) {
+        let share = match share {
+            Some(share) => share,
+            None => {
+                *self = LayerAllocation::default();
+                return;
+            }
+        };
+        if layers.is_empty() {
+            return;
+        }
+        let fitting = layers
+            .iter()
+            .rposition(|(_, bitrate)| *bitrate <= share)
+            .unwrap_or(0);
+        let with_headroom = layers
+            .iter()
+            .rposition(|(_, bitrate)| *bitrate as f64 * UPGRADE_HEADROOM <= share as f64);
+        let current = self
+            .rid
+            .as_ref()
+            .and_then(|rid| layers.iter().position(|(layer, _)| layer == rid));
+
+        let (chosen, reason) = match current {
+            // downgrades can't wait, the subscriber is losing packets already
+            Some(current) if current > fitting => {
+                self.upgrade_since = None;
+                (fitting, LayerReason::FitsEstimate)
+            }
+            Some(current) => match with_headroom {
+                Some(better) if better > current => {
+                    let since = *self.upgrade_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= UPGRADE_HOLD {
+                        self.upgrade_since = None;
+                        (better, LayerReason::FitsEstimate)
+                    } else {
+                        (current, LayerReason::UpgradePending)
+                    }
+                }
+                _ => {
+                    self.upgrade_since = None;
+                    (current, LayerReason::FitsEstimate)
+                }
+            },
+            None => (fitting, LayerReason::FitsEstimate),
+        };
+        let reason = if layers[chosen].1 > share {
+            LayerReason::Congested
+        } else {
+            reason
+        };
+        self.rid = Some(layers[chosen].0.clone());
+        self.reason = reason;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtcp::reception_report::ReceptionReport;
+    use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+        RunLengthChunk, StatusVectorChunk,
+    };
+
+    fn layers() -> Vec<(String, u64)> {
+        vec![
+            ("q".to_owned(), 150_000),
+            ("h".to_owned(), 500_000),
+            ("f".to_owned(), 1_500_000),
+        ]
+    }
+
+    #[test]
+    fn allocates_layers_with_hysteresis() {
+        use LayerReason::*;
+        // Layer allocated before, if any, for how many seconds an upgrade was pending and the
+        // share, then the layer picked, the reason and whether an upgrade is pending.
+        let cases = [
+            ("h", None, None, "", NoEstimate, false),
+            ("", None, Some(600_000), "h", FitsEstimate, false),
+            ("", None, Some(100_000), "q", Congested, false),
+            ("x", None, Some(2_000_000), "f", FitsEstimate, false),
+            // Downgrades happen at once.
+            ("f", None, Some(600_000), "h", FitsEstimate, false),
+            ("h", Some(3), Some(100_000), "q", Congested, false),
+            // Upgrades need 25% headroom, held for 4 seconds.
+            ("q", None, Some(600_000), "q", FitsEstimate, false),
+            ("q", None, Some(625_000), "q", UpgradePending, true),
+            ("q", Some(3), Some(625_000), "q", UpgradePending, true),
+            ("q", Some(4), Some(625_000), "h", FitsEstimate, false),
+            ("q", Some(4), Some(1_875_000), "f", FitsEstimate, false),
+            ("h", Some(4), Some(1_800_000), "h", FitsEstimate, false),
+            // A pending upgrade starts over once the headroom is gone.
+            ("q", Some(3), Some(600_000), "q", FitsEstimate, false),
+        ];
+        let rid = |rid: &str| Some(rid.to_owned()).filter(|rid| !rid.is_empty());
+        for (current, pending, share, picked, reason, pending_after) in cases {
+            let mut allocation = LayerAllocation {
+                rid: rid(current),
+                reason: FitsEstimate,
+                upgrade_since: pending.map(|pending| Instant::now() - Duration::from_secs(pending)),
+            };
+            allocation.allocate(&layers(), share);
+            let case = (current, pending, share);
+            assert_eq!(allocation.rid, rid(picked), "{:?}", case);
+            assert_eq!(allocation.reason, reason, "{:?}", case);
+            assert_eq!(
+                allocation.upgrade_since.is_some(),
+                pending_after,
+                "{:?}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn counts_lost_packets_in_twcc_feedback() {
+        use SymbolTypeTcc::*;
+        let run = |packet_status_symbol, run_length| {
+            PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+                packet_status_symbol,
+                run_length,
+                ..Default::default()
+            })
+        };
+        let vector = |symbol_list: &[SymbolTypeTcc]| {
+            PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+                symbol_list: symbol_list.to_vec(),
+                ..Default::default()
+            })
+        };
+        let cases = [
+            (vec![], 0, None),
+            (vec![run(PacketReceivedSmallDelta, 10)], 10, Some(0.0)),
+            (
+                vec![run(PacketReceivedSmallDelta, 6), run(PacketNotReceived, 2)],
+                8,
+                Some(0.25),
+            ),
+            (
+                vec![vector(&[
+                    PacketNotReceived,
+                    PacketReceivedSmallDelta,
+                    PacketReceivedLargeDelta,
+                    PacketNotReceived,
+                ])],
+                4,
+                Some(0.5),
+            ),
+            // Padding symbols past the status count aren't packets, whatever chunk they are in.
+            (
+                vec![
+                    run(PacketReceivedSmallDelta, 3),
+                    run(PacketNotReceived, 100),
+                ],
+                5,
+                Some(0.4),
+            ),
+            (
+                vec![vector(&[
+                    PacketReceivedSmallDelta,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                    PacketNotReceived,
+                ])],
+                2,
+                Some(0.5),
+            ),
+        ];
+        for (packet_chunks, packet_status_count, loss) in cases {
+            let feedback = TransportLayerCc {
+                packet_status_count,
+                packet_chunks,
+                ..Default::default()
+            };
+            assert_eq!(twcc_loss(&feedback), loss, "{:?}", feedback.packet_chunks);
+        }
+    }
+
+    #[test]
+    fn loss_drives_the_estimate() {
+        // Loss reported over one interval, and the estimate grown from the initial one.
+        let cases: &[(&[f64], u64)] = &[
+            (&[0.0], 1_080_000),
+            (&[0.01, 0.0], 1_080_000),
+            (&[0.02], 1_000_000),
+            (&[0.10], 1_000_000),
+            (&[0.04, 0.0, 0.02], 1_000_000),
+            (&[0.2], 900_000),
+            (&[0.5, 0.3], 800_000),
+        ];
+        for (losses, bitrate) in cases {
+            let mut estimator = BandwidthEstimator::default();
+            for loss in *losses {
+                estimator.on_loss(*loss);
+            }
+            let estimate = estimator.update();
+            assert_eq!(estimate.bitrate, Some(*bitrate), "{:?}", losses);
+            assert_eq!(estimate.source, EstimateSource::Loss);
+        }
+    }
+
+    #[test]
+    fn estimate_stays_within_bounds() {
+        let mut estimator = BandwidthEstimator::default();
+        for _ in 0..100 {
+            estimator.on_loss(0.0);
+            estimator.update();
+        }
+        assert_eq!(estimator.estimate().bitrate, Some(MAX_BITRATE));
+        for _ in 0..100 {
+            estimator.on_loss(1.0);
+            estimator.update();
+        }
+        assert_eq!(estimator.estimate().bitrate, Some(MIN_BITRATE));
+        // Without new feedback the estimate and the loss stay as they were.
+        let estimate = estimator.update();
+        assert_eq!(estimate.bitrate, Some(MIN_BITRATE));
+        assert_eq!(estimate.loss, 1.0);
+    }
+
+    #[test]
+    fn remb_caps_the_estimate() {
+        let mut estimator = BandwidthEstimator::default();
+        assert_eq!(estimator.update().source, EstimateSource::None);
+
+        let remb = ReceiverEstimatedMaximumBitrate {
+            bitrate: 400_000.0,
+            ..Default::default()
+        };
+        // fraction_lost is in 1/256ths
+        let report = ReceiverReport {
+            reports: vec![ReceptionReport {
+                fraction_lost: 64,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        estimator.on_rtcp(&[Box::new(remb), Box::new(report)]);
+        let estimate = estimator.update();
+        assert_eq!(estimate.bitrate, Some(400_000));
+        assert_eq!(estimate.source, EstimateSource::Remb);
+        assert_eq!(estimate.loss, 0.25);
+
+        // REMB above the loss based estimate doesn't raise it, and expires.
+        estimator.remb = Some((5_000_000, Instant::now()));
+        assert_eq!(estimator.update().source, EstimateSource::Loss);
+        estimator.remb = Some((100_000, Instant::now() - REMB_TIMEOUT));
+        assert_eq!(estimator.update().bitrate, Some(875_000));
+    }
+}
@@ -0,0 +1,505 @@
+//! Codecs offered by the server and the codec every member of a group has to agree on.
+//!
+//! The SFU forwards RTP untouched, so a subscriber can only receive a track if it negotiated the
+//! exact codec the publisher sends. The first offer sending a kind of media into a group pins the
+//! codec of that kind it prefers, later offers must support it and are answered with it alone.
+//! The pin is released once the last track of its kind left the group.
+use crate::config::CodecConfig;
+use anyhow::{anyhow, Result};
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_AV1, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA,
+    MIME_TYPE_PCMU, MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::rtp_transceiver::RTCPFeedback;
+use webrtc::sdp::description::media::MediaDescription;
+use webrtc::sdp::description::session::SessionDescription;
+
+/// Codec pinned for each media kind of a group.
+#[derive(Debug, Clone, Default)]
+pub struct GroupCodecs {
+    audio: Option<RTCRtpCodecCapability>,
+    video: Option<RTCRtpCodecCapability>,
+}
+
+impl GroupCodecs {
+    pub fn get(&self, kind: RTPCodecType) -> Option<&RTCRtpCodecCapability> {
+        match kind {
+            RTPCodecType::Audio => self.audio.as_ref(),
+            RTPCodecType::Video => self.video.as_ref(),
+            RTPCodecType::Unspecified => None,
+        }
+    }
+
+    /// Pins `codec` for `kind` unless a codec was pinned already, returns the pinned codec.
+    pub fn pin(
+        &mut self,
+        kind: RTPCodecType,
+        codec: &RTCRtpCodecCapability,
+    ) -> Option<&RTCRtpCodecCapability> {
+        let pinned = match kind {
+            RTPCodecType::Audio => &mut self.audio,
+            RTPCodecType::Video => &mut self.video,
+            RTPCodecType::Unspecified => return None,
+        };
+        Some(pinned.get_or_insert_with(|| codec.clone()))
+    }
+
+    /// Unpins the codecs of the kinds none of the `published` tracks is of anymore.
+    pub fn release(&mut self, published: &[RTPCodecType]) {
+        if !published.contains(&RTPCodecType::Audio) {
+            self.audio = None;
+        }
+        if !published.contains(&RTPCodecType::Video) {
+            self.video = None;
+        }
+    }
+}
+
+struct CodecDefaults {
+    name: &'static str,
+    mime_type: &'static str,
+    clock_rate: u32,
+    channels: u16,
+    payload_type: u8,
+    fmtp: &'static str,
+}
+
+const KNOWN_CODECS: &[CodecDefaults] = &[
+    CodecDefaults {
+        name: "opus",
+        mime_type: MIME_TYPE_OPUS,
+        clock_rate: 48000,
+        channels: 2,
+        payload_type: 111,
+        fmtp: "minptime=10;useinbandfec=1",
+    },
+    CodecDefaults {
+        name: "g722",
+        mime_type: MIME_TYPE_G722,
+        clock_rate: 8000,
+        channels: 0,
+        payload_type: 9,
+        fmtp: "",
+    },
+    CodecDefaults {
+        name: "pcmu",
+        mime_type: MIME_TYPE_PCMU,
+        clock_rate: 8000,
+        channels: 0,
+        payload_type: 0,
+        fmtp: "",
+    },
+    CodecDefaults {
+        name: "pcma",
+        mime_type: MIME_TYPE_PCMA,
+        clock_rate: 8000,
+        channels: 0,
+        payload_type: 8,
+        fmtp: "",
+    },
+    CodecDefaults {
+        name: "vp8",
+        mime_type: MIME_TYPE_VP8,
+        clock_rate: 90000,
+        channels: 0,
+        payload_type: 96,
+        fmtp: "",
+    },
+    CodecDefaults {
+        name: "vp9",
+        mime_type: MIME_TYPE_VP9,
+        clock_rate: 90000,
+        channels: 0,
+        payload_type: 98,
+        fmtp: "profile-id=0",
+    },
+    CodecDefaults {
+        name: "h264",
+        mime_type: MIME_TYPE_H264,
+        clock_rate: 90000,
+        channels: 0,
+        payload_type: 102,
+        fmtp: "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f",
+    },
+    CodecDefaults {
+        name: "av1",
+        mime_type: MIME_TYPE_AV1,
+        clock_rate: 90000,
+        channels: 0,
+        payload_type: 41,
+        fmtp: "profile-id=0",
+    },
+];
+
+fn video_rtcp_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_string(),
+        parameter: parameter.to_string(),
+    })
+    .collect()
+}
+
+pub fn kind_of(mime_type: &str) -> RTPCodecType {
+    match mime_type.split('/').next() {
+        Some(kind) => RTPCodecType::from(kind),
+        None => RTPCodecType::Unspecified,
+    }
+}
+
+/// Builds the codec parameters for a configured codec, filling in the defaults of its name.
+pub fn codec_parameters(config: &CodecConfig) -> Result<RTCRtpCodecParameters> {
+    let defaults = KNOWN_CODECS
+        .iter()
+        .find(|codec| codec.name.eq_ignore_ascii_case(&config.name))
+        .ok_or_else(|| {
+            let names: Vec<_> = KNOWN_CODECS.iter().map(|codec| codec.name).collect();
+            anyhow!(
+                "unknown codec {:?}, expected one of {}",
+                config.name,
+                names.join(", ")
+            )
+        })?;
+    let rtcp_feedback = if kind_of(defaults.mime_type) == RTPCodecType::Video {
+        video_rtcp_feedback()
+    } else {
+        vec![]
+    };
+    Ok(RTCRtpCodecParameters {
+        capability: RTCRtpCodecCapability {
+            mime_type: defaults.mime_type.to_owned(),
+            clock_rate: defaults.clock_rate,
+            channels: config.channels.unwrap_or(defaults.channels),
+            sdp_fmtp_line: config
+                .fmtp
+                .clone()
+                .unwrap_or_else(|| defaults.fmtp.to_owned()),
+            rtcp_feedback,
+        },
+        payload_type: config.payload_type.unwrap_or(defaults.payload_type),
+        ..Default::default()
+    })
+}
+
+/// Registers `codecs` in their order of priority, or the webrtc defaults when none are configured.
+pub fn register_codecs(
+    media_engine: &mut MediaEngine,
+    codecs: &[RTCRtpCodecParameters],
+) -> Result<()> {
+    if codecs.is_empty() {
+        media_engine.register_default_codecs()?;
+        return Ok(());
+    }
+    for codec in codecs {
+        media_engine.register_codec(codec.clone(), kind_of(&codec.capability.mime_type))?;
+    }
+    Ok(())
+}
+
+fn fmtp_parameter<'a>(fmtp: &'a str, key: &str) -> Option<&'a str> {
+    fmtp.split(';').find_map(|parameter| {
+        let (name, value) = parameter.trim().split_once('=')?;
+        if name.eq_ignore_ascii_case(key) {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether RTP of codec `a` can be forwarded to a peer which negotiated `b`.
+pub fn codecs_match(a: &RTCRtpCodecCapability, b: &RTCRtpCodecCapability) -> bool {
+    if !a.mime_type.eq_ignore_ascii_case(&b.mime_type) || a.clock_rate != b.clock_rate {
+        return false;
+    }
+    if a.mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        let packetization_mode = |fmtp: &str| {
+            fmtp_parameter(fmtp, "packetization-mode")
+                .unwrap_or("0")
+                .to_owned()
+        };
+        // The profile are the first two bytes of profile-level-id, the level may differ.
+        let profile = |fmtp: &str| {
+            fmtp_parameter(fmtp, "profile-level-id")
+                .and_then(|id| id.get(..4))
+                .map(str::to_ascii_lowercase)
+        };
+        return packetization_mode(&a.sdp_fmtp_line) == packetization_mode(&b.sdp_fmtp_line)
+            && profile(&a.sdp_fmtp_line) == profile(&b.sdp_fmtp_line);
+    }
+    if a.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9)
+        || a.mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1)
+    {
+        let profile = |fmtp: &str| fmtp_parameter(fmtp, "profile-id").unwrap_or("0").to_owned();
+        return profile(&a.sdp_fmtp_line) == profile(&b.sdp_fmtp_line);
+    }
+    true
+}
+
+/// Whether the remote side of `media` sends on it, a media section without direction is sendrecv.
+pub fn is_sending(media: &MediaDescription) -> bool {
+    media.attribute("sendrecv").is_some()
+        || media.attribute("sendonly").is_some()
+        || (media.attribute("recvonly").is_none() && media.attribute("inactive").is_none())
+}
+
+/// Codecs listed by `media`, a media section of `description`, from the most preferred.
+pub fn media_codecs(
+    description: &SessionDescription,
+    media: &MediaDescription,
+) -> Vec<RTCRtpCodecCapability> {
+    let kind = RTPCodecType::from(media.media_name.media.as_str());
+    media
+        .media_name
+        .formats
+        .iter()
+        .filter_map(|format| format.parse::<u8>().ok())
+        .filter_map(|payload_type| description.get_codec_for_payload_type(payload_type).ok())
+        .map(|codec| RTCRtpCodecCapability {
+            mime_type: format!("{}/{}", kind, codec.name),
+            clock_rate: codec.clock_rate,
+            sdp_fmtp_line: codec.fmtp,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Makes sure an offer supports every codec pinned by the group for the kinds it negotiates.
+pub fn check_offer_codecs(offer: &SessionDescription, pinned: &GroupCodecs) -> Result<()> {
+    for media in &offer.media_descriptions {
+        let kind = RTPCodecType::from(media.media_name.media.as_str());
+        let pinned_codec = match pinned.get(kind) {
+            Some(codec) => codec,
+            None => continue,
+        };
+        let supported = media_codecs(offer, media)
+            .iter()
+            .any(|offered| codecs_match(pinned_codec, offered));
+        if !supported {
+            return Err(anyhow!(
+                "the group uses {} {} which the offer does not support",
+                pinned_codec.mime_type,
+                pinned_codec.sdp_fmtp_line
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Pins the codec `offer` prefers out of the `supported` ones for every kind it sends that no
+/// codec is pinned for yet.
+pub fn pin_offer_codecs(
+    offer: &SessionDescription,
+    pinned: &mut GroupCodecs,
+    supported: &[RTCRtpCodecCapability],
+) {
+    for media in offer
+        .media_descriptions
+        .iter()
+        .filter(|media| is_sending(media))
+    {
+        let kind = RTPCodecType::from(media.media_name.media.as_str());
+        if pinned.get(kind).is_some() {
+            continue;
+        }
+        let preferred = media_codecs(offer, media).into_iter().find_map(|offered| {
+            supported
+                .iter()
+                .find(|supported| codecs_match(supported, &offered))
+        });
+        if let Some(codec) = preferred {
+            pinned.pin(kind, codec);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+    fn codec(mime_type: &str, clock_rate: u32, sdp_fmtp_line: &str) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate,
+            sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn h264(sdp_fmtp_line: &str) -> RTCRtpCodecCapability {
+        codec(MIME_TYPE_H264, 90000, sdp_fmtp_line)
+    }
+
+    /// An offer sending opus and receiving VP8 or H264, preferring VP8.
+    fn offer(video_direction: &str) -> SessionDescription {
+        let sdp = [
+            "v=0",
+            "o=- 0 0 IN IP4 127.0.0.1",
+            "s=-",
+            "t=0 0",
+            "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+            "c=IN IP4 0.0.0.0",
+            "a=rtpmap:111 opus/48000/2",
+            "a=fmtp:111 minptime=10;useinbandfec=1",
+            "a=sendrecv",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96 102",
+            "c=IN IP4 0.0.0.0",
+            "a=rtpmap:96 VP8/90000",
+            "a=rtpmap:102 H264/90000",
+            "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+            video_direction,
+            "",
+        ]
+        .join("\r\n");
+        RTCSessionDescription::offer(sdp)
+            .unwrap()
+            .unmarshal()
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_codecs() {
+        let cases = [
+            (
+                codec(MIME_TYPE_VP8, 90000, ""),
+                codec("video/vp8", 90000, ""),
+                true,
+            ),
+            (
+                codec(MIME_TYPE_VP8, 90000, ""),
+                codec(MIME_TYPE_VP9, 90000, ""),
+                false,
+            ),
+            (
+                codec(MIME_TYPE_OPUS, 48000, ""),
+                codec(MIME_TYPE_OPUS, 16000, ""),
+                false,
+            ),
+            // Only the profile of profile-level-id counts, the level may differ.
+            (
+                h264("packetization-mode=1;profile-level-id=42e01f"),
+                h264("profile-level-id=42E034;packetization-mode=1"),
+                true,
+            ),
+            (
+                h264("packetization-mode=1;profile-level-id=42e01f"),
+                h264("packetization-mode=1;profile-level-id=640c1f"),
+                false,
+            ),
+            (
+                h264("packetization-mode=1;profile-level-id=42e01f"),
+                h264("packetization-mode=1"),
+                false,
+            ),
+            // packetization-mode defaults to 0.
+            (
+                h264("packetization-mode=0;profile-level-id=42e01f"),
+                h264("profile-level-id=42e01f"),
+                true,
+            ),
+            (
+                h264("packetization-mode=1;profile-level-id=42e01f"),
+                h264("profile-level-id=42e01f"),
+                false,
+            ),
+            // profile-id defaults to 0.
+            (
+                codec(MIME_TYPE_VP9, 90000, "profile-id=0"),
+                codec(MIME_TYPE_VP9, 90000, ""),
+                true,
+            ),
+            (
+                codec(MIME_TYPE_VP9, 90000, "profile-id=0"),
+                codec(MIME_TYPE_VP9, 90000, "profile-id=2"),
+                false,
+            ),
+            (
+                codec(MIME_TYPE_AV1, 90000, "profile-id=1"),
+                codec(MIME_TYPE_AV1, 90000, ""),
+                false,
+            ),
+        ];
+        for (a, b, matching) in cases {
+            assert_eq!(codecs_match(&a, &b), matching, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn checks_offers_against_the_pinned_codecs() {
+        let offer = offer("a=recvonly");
+        let mut pinned = GroupCodecs::default();
+        assert!(check_offer_codecs(&offer, &pinned).is_ok());
+
+        pinned.pin(
+            RTPCodecType::Video,
+            &h264("packetization-mode=1;profile-level-id=42e01f"),
+        );
+        pinned.pin(RTPCodecType::Audio, &codec(MIME_TYPE_OPUS, 48000, ""));
+        assert!(check_offer_codecs(&offer, &pinned).is_ok());
+
+        let mut pinned = GroupCodecs::default();
+        pinned.pin(RTPCodecType::Video, &codec(MIME_TYPE_AV1, 90000, ""));
+        let err = check_offer_codecs(&offer, &pinned).unwrap_err();
+        assert!(err.to_string().contains(MIME_TYPE_AV1), "{}", err);
+    }
+
+    #[test]
+    fn pins_the_preferred_codec_of_offers_sending_media() {
+        let supported = [
+            codec(MIME_TYPE_OPUS, 48000, "minptime=10;useinbandfec=1"),
+            h264("level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"),
+            codec(MIME_TYPE_VP8, 90000, ""),
+        ];
+        // Nothing is pinned for the video the offer only receives.
+        let mut pinned = GroupCodecs::default();
+        pin_offer_codecs(&offer("a=recvonly"), &mut pinned, &supported);
+        assert_eq!(pinned.get(RTPCodecType::Audio), Some(&supported[0]));
+        assert_eq!(pinned.get(RTPCodecType::Video), None);
+
+        // The offer's preference wins over the server's.
+        pin_offer_codecs(&offer("a=sendonly"), &mut pinned, &supported);
+        assert_eq!(pinned.get(RTPCodecType::Video), Some(&supported[2]));
+
+        // A codec pinned first stays, the next offer is answered with it.
+        let mut pinned = GroupCodecs::default();
+        pinned.pin(RTPCodecType::Video, &supported[1]);
+        pin_offer_codecs(&offer("a=sendonly"), &mut pinned, &supported);
+        assert_eq!(pinned.get(RTPCodecType::Video), Some(&supported[1]));
+
+        // Kinds without a codec the server supports stay unpinned.
+        let mut pinned = GroupCodecs::default();
+        pin_offer_codecs(&offer("a=sendrecv"), &mut pinned, &supported[1..]);
+        assert_eq!(pinned.get(RTPCodecType::Audio), None);
+        assert_eq!(pinned.get(RTPCodecType::Video), Some(&supported[2]));
+    }
+
+    #[test]
+    fn releases_pins_once_the_last_track_of_a_kind_is_gone() {
+        let mut pinned = GroupCodecs::default();
+        pinned.pin(RTPCodecType::Audio, &codec(MIME_TYPE_OPUS, 48000, ""));
+        pinned.pin(RTPCodecType::Video, &codec(MIME_TYPE_VP8, 90000, ""));
+        pinned.release(&[RTPCodecType::Audio, RTPCodecType::Audio]);
+        assert!(pinned.get(RTPCodecType::Audio).is_some());
+        assert!(pinned.get(RTPCodecType::Video).is_none());
+
+        // The next publisher picks the codec again.
+        pinned.pin(RTPCodecType::Video, &codec(MIME_TYPE_VP9, 90000, ""));
+        assert_eq!(
+            pinned.get(RTPCodecType::Video).unwrap().mime_type,
+            MIME_TYPE_VP9
+        );
+        pinned.release(&[]);
+        assert!(pinned.get(RTPCodecType::Audio).is_none());
+        assert!(pinned.get(RTPCodecType::Video).is_none());
+    }
+}
@@ -0,0 +1,480 @@
+//! Configuration file of the signal server, see `signal_server.example.toml` for a commented
+//! example. Every section is optional. Command line flags and their environment variables take
+//! precedence over the file.
+use crate::codecs;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecParameters;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub ice_servers: Vec<IceServerConfig>,
+    /// Codecs in order of priority, the webrtc defaults are used when empty.
+    pub codecs: Vec<CodecConfig>,
+    pub webrtc: WebRTCConfig,
+    pub turn: TurnConfig,
+    pub groups: GroupsConfig,
+    pub auth: AuthConfig,
+    pub admin: AdminConfig,
+    pub recording: RecordingConfig,
+    pub debug: DebugConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            ice_servers: vec![IceServerConfig {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                username: None,
+                credential: None,
+            }],
+            codecs: vec![],
+            webrtc: WebRTCConfig::default(),
+            turn: TurnConfig::default(),
+            groups: GroupsConfig::default(),
+            auth: AuthConfig::default(),
+            admin: AdminConfig::default(),
+            recording: RecordingConfig::default(),
+            debug: DebugConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub port: u16,
+    /// Seconds a disconnected client may resume its session, 0 disables resumption.
+    pub resume_grace_period: u64,
+    /// Origins browser pages may use `/signal` and WHIP from, e.g. `https://app.example.com`, `*`
+    /// allowing any. Requests without an `Origin` don't come from browsers and are always served.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: "127.0.0.1".to_owned(),
+            port: 9999,
+            resume_grace_period: 30,
+            allowed_origins: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CodecConfig {
+    /// One of opus, g722, pcmu, pcma, vp8, vp9, h264 or av1.
+    pub name: String,
+    pub payload_type: Option<u8>,
+    /// Format parameters replacing the codec's default, e.g. `stereo=1;useinbandfec=1;usedtx=1`.
+    pub fmtp: Option<String>,
+    pub channels: Option<u16>,
+}
+
+/// Transport policy shared by every peer connection.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRTCConfig {
+    /// Networks ICE gathers candidates on, any of udp4, udp6, tcp4 and tcp6.
+    pub network_types: Vec<NetworkType>,
+    pub mdns: MdnsMode,
+    /// Lowest UDP port peer connections bind, together with `udp_port_max`.
+    pub udp_port_min: Option<u16>,
+    pub udp_port_max: Option<u16>,
+    /// Single UDP port every peer connection is multiplexed over, excludes a port range.
+    pub udp_mux_port: Option<u16>,
+    /// Public ips advertised in place of the local ips of host candidates, either `public` or
+    /// `public/local` to map a specific local ip.
+    pub nat_1to1_ips: Vec<String>,
+}
+
+impl Default for WebRTCConfig {
+    fn default() -> Self {
+        WebRTCConfig {
+            network_types: vec![NetworkType::Udp4, NetworkType::Udp6],
+            mdns: MdnsMode::default(),
+            udp_port_min: None,
+            udp_port_max: None,
+            udp_mux_port: None,
+            nat_1to1_ips: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MdnsMode {
+    /// Discards remote `.local` candidates, host candidates carry their ip.
+    Disabled,
+    /// Resolves remote `.local` candidates, host candidates carry their ip.
+    #[default]
+    QueryOnly,
+    /// Resolves remote `.local` candidates and hides the ip of host candidates behind one.
+    QueryAndGather,
+}
+
+impl From<MdnsMode> for MulticastDnsMode {
+    fn from(mode: MdnsMode) -> Self {
+        match mode {
+            MdnsMode::Disabled => MulticastDnsMode::Disabled,
+            MdnsMode::QueryOnly => MulticastDnsMode::QueryOnly,
+            MdnsMode::QueryAndGather => MulticastDnsMode::QueryAndGather,
+        }
+    }
+}
+
+/// Embedded TURN/STUN server handing out per-session credentials.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    pub enabled: bool,
+    /// UDP address the server listens on.
+    pub listen: String,
+    /// TCP address the server listens on as well, e.g. `0.0.0.0:443` for clients only allowed
+    /// TCP to port 443. TCP is off when unset.
+    pub tcp_listen: Option<String>,
+    /// TCP connections accepted at most, further ones are closed right away.
+    pub max_tcp_connections: usize,
+    /// Ip clients reach the server and its relays at, required when enabled.
+    pub public_ip: Option<String>,
+    pub realm: String,
+    /// Secret credentials are derived from, a random one is generated when unset.
+    pub secret: Option<String>,
+    /// Seconds issued credentials stay valid.
+    pub credential_ttl: u64,
+    /// UDP port range of relay allocations, any port when unset.
+    pub relay_port_min: Option<u16>,
+    pub relay_port_max: Option<u16>,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        TurnConfig {
+            enabled: false,
+            listen: "0.0.0.0:3478".to_owned(),
+            tcp_listen: None,
+            max_tcp_connections: 1000,
+            public_ip: None,
+            realm: "open-telepresence".to_owned(),
+            secret: None,
+            credential_ttl: 86400,
+            relay_port_min: None,
+            relay_port_max: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupsConfig {
+    /// Seconds an empty group is kept before it is torn down.
+    pub grace_period: u64,
+    /// Maximum number of clients in a single group, unlimited when unset.
+    pub max_clients: Option<usize>,
+    /// Video tracks forwarded to each client unless it sends its own `last-n`, all when unset.
+    pub last_n: Option<usize>,
+}
+
+impl Default for GroupsConfig {
+    fn default() -> Self {
+        GroupsConfig {
+            grace_period: 30,
+            max_clients: None,
+            last_n: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Shared secret verifying client tokens, authentication is disabled when unset.
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token of the admin HTTP API, the API is disabled when unset.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Directory group recordings are written to, recording is disabled when unset.
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugConfig {
+    /// Directory every peer connection dumps the packets it receives to, off when unset.
+    pub rtp_dump_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `tracing` filter directives, `RUST_LOG` overrides it.
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: "debug".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("unable to parse config file {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.server
+            .addr
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("server.addr: {:?} is not an ip address", self.server.addr))?;
+        for (i, origin) in self.server.allowed_origins.iter().enumerate() {
+            if origin != "*" && !is_origin(origin) {
+                bail!(
+                    "server.allowed_origins[{}]: {:?} is not an origin like https://example.com",
+                    i,
+                    origin
+                );
+            }
+        }
+        for (i, server) in self.ice_servers.iter().enumerate() {
+            server
+                .validate()
+                .with_context(|| format!("ice_servers[{}]", i))?;
+        }
+        let mut payload_types = HashSet::new();
+        for (i, codec) in self.codecs.iter().enumerate() {
+            let parameters =
+                codecs::codec_parameters(codec).with_context(|| format!("codecs[{}]", i))?;
+            if !payload_types.insert(parameters.payload_type) {
+                bail!(
+                    "codecs[{}]: payload type {} is used twice, set payload_type explicitly",
+                    i,
+                    parameters.payload_type
+                );
+            }
+        }
+        if self.webrtc.network_types.is_empty() {
+            bail!("webrtc.network_types: at least one network type is required");
+        }
+        if self
+            .webrtc
+            .network_types
+            .contains(&NetworkType::Unspecified)
+        {
+            bail!("webrtc.network_types: expected udp4, udp6, tcp4 or tcp6");
+        }
+        self.webrtc.validate().context("webrtc")?;
+        if self.turn.enabled {
+            self.turn.validate().context("turn")?;
+        }
+        if self.groups.max_clients == Some(0) {
+            bail!("groups.max_clients: must be at least 1");
+        }
+        if let Some(secret) = &self.auth.secret {
+            if secret.is_empty() {
+                bail!("auth.secret: must not be empty");
+            }
+        }
+        if self.admin.token.as_deref() == Some("") {
+            bail!("admin.token: must not be empty");
+        }
+        if self.recording.directory.as_deref() == Some(Path::new("")) {
+            bail!("recording.directory: must not be empty");
+        }
+        if self.debug.rtp_dump_directory.as_deref() == Some(Path::new("")) {
+            bail!("debug.rtp_dump_directory: must not be empty");
+        }
+        EnvFilter::try_new(&self.log.filter)
+            .with_context(|| format!("log.filter: {:?} is not a valid filter", self.log.filter))?;
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        // validate() made sure the address parses
+        let addr = self.server.addr.parse::<IpAddr>().unwrap();
+        SocketAddr::new(addr, self.server.port)
+    }
+
+    pub fn resume_grace_period(&self) -> Duration {
+        Duration::from_secs(self.server.resume_grace_period)
+    }
+
+    pub fn group_grace_period(&self) -> Duration {
+        Duration::from_secs(self.groups.grace_period)
+    }
+
+    pub fn rtc_codecs(&self) -> Vec<RTCRtpCodecParameters> {
+        // validate() made sure every codec is known
+        self.codecs
+            .iter()
+            .map(|codec| codecs::codec_parameters(codec).unwrap())
+            .collect()
+    }
+
+    pub fn rtc_ice_servers(&self) -> Vec<RTCIceServer> {
+        self.ice_servers
+            .iter()
+            .map(|server| RTCIceServer {
+                urls: server.urls.clone(),
+                username: server.username.clone().unwrap_or_default(),
+                credential: server.credential.clone().unwrap_or_default(),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+impl WebRTCConfig {
+    fn validate(&self) -> Result<()> {
+        match (self.udp_port_min, self.udp_port_max) {
+            (Some(min), Some(max)) if min > max => {
+                bail!("udp_port_min: {} is above udp_port_max {}", min, max)
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                bail!("udp_port_min and udp_port_max have to be set together")
+            }
+            _ => {}
+        }
+        if self.udp_mux_port.is_some() && self.udp_port_min.is_some() {
+            bail!("udp_mux_port: can't be combined with a udp port range");
+        }
+        if self.udp_mux_port == Some(0) {
+            bail!("udp_mux_port: must not be 0");
+        }
+        for mapping in &self.nat_1to1_ips {
+            let (public, local) = match mapping.split_once('/') {
+                Some((public, local)) => (public, Some(local)),
+                None => (mapping.as_str(), None),
+            };
+            let public = public
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("nat_1to1_ips: {:?} is not an ip address", public))?;
+            if let Some(local) = local {
+                let local = local
+                    .parse::<IpAddr>()
+                    .map_err(|_| anyhow!("nat_1to1_ips: {:?} is not an ip address", local))?;
+                if public.is_ipv4() != local.is_ipv4() {
+                    bail!("nat_1to1_ips: {} maps between ip versions", mapping);
+                }
+            }
+        }
+        if !self.nat_1to1_ips.is_empty() && matches!(self.mdns, MdnsMode::QueryAndGather) {
+            bail!("nat_1to1_ips: can't be combined with mdns = \"query-and-gather\"");
+        }
+        Ok(())
+    }
+}
+
+impl TurnConfig {
+    fn validate(&self) -> Result<()> {
+        self.listen
+            .parse::<SocketAddr>()
+            .map_err(|_| anyhow!("listen: {:?} is not a socket address", self.listen))?;
+        if let Some(tcp_listen) = &self.tcp_listen {
+            tcp_listen
+                .parse::<SocketAddr>()
+                .map_err(|_| anyhow!("tcp_listen: {:?} is not a socket address", tcp_listen))?;
+            if self.max_tcp_connections == 0 {
+                bail!("max_tcp_connections: has to be at least 1 with tcp_listen set");
+            }
+        }
+        let public_ip = self
+            .public_ip
+            .as_deref()
+            .ok_or_else(|| anyhow!("public_ip: required when the turn server is enabled"))?;
+        public_ip
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("public_ip: {:?} is not an ip address", public_ip))?;
+        if self.secret.as_deref() == Some("") {
+            bail!("secret: must not be empty");
+        }
+        if self.credential_ttl == 0 {
+            bail!("credential_ttl: must be at least 1");
+        }
+        match (self.relay_port_min, self.relay_port_max) {
+            (Some(min), Some(max)) if min == 0 || min > max => {
+                bail!(
+                    "relay_port_min..relay_port_max: {}..{} is not a valid range",
+                    min,
+                    max
+                )
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                bail!("relay_port_min and relay_port_max have to be set together")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl IceServerConfig {
+    fn validate(&self) -> Result<()> {
+        if self.urls.is_empty() {
+            bail!("urls: at least one url is required");
+        }
+        for url in &self.urls {
+            let scheme = url.split(':').next().unwrap_or_default();
+            match scheme {
+                "stun" | "stuns" => {}
+                "turn" | "turns" => {
+                    if self.username.is_none() || self.credential.is_none() {
+                        bail!("{}: turn servers need a username and a credential", url);
+                    }
+                }
+                _ => bail!("{}: expected a stun:, stuns:, turn: or turns: url", url),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `origin` is a scheme and a host with an optional port, as browsers send it.
+fn is_origin(origin: &str) -> bool {
+    let authority = match origin.split_once("://") {
+        Some((scheme, authority)) if !scheme.is_empty() => authority,
+        _ => return false,
+    };
+    match origin.parse::<warp::http::Uri>() {
+        Ok(uri) => uri.authority().map(|authority| authority.as_str()) == Some(authority),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,360 @@
+//! File formats the recorder writes tracks in: IVF for VP8 and VP9, Ogg for Opus.
+//!
+//! Both take whole frames with their presentation time in units of the RTP clock of the track,
+//! counted from the start of the recording.
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub trait Container: Send {
+    /// Appends a frame presented `pts` clock units after the start of the recording.
+    fn write_frame(&mut self, pts: u64, frame: &[u8]) -> io::Result<()>;
+
+    /// Completes the file, nothing is written after.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+const IVF_HEADER_SIZE: u16 = 32;
+/// Offset of the frame count in the IVF header.
+const IVF_FRAME_COUNT_OFFSET: u64 = 24;
+
+/// IVF file of VP8 or VP9 frames, stamped in the RTP clock rate.
+pub struct IvfWriter {
+    file: BufWriter<File>,
+    frames: u32,
+}
+
+impl IvfWriter {
+    /// Creates the file at `path` for frames of codec `fourcc`, `VP80` or `VP90`.
+    pub fn create(path: &Path, fourcc: &[u8; 4], clock_rate: u32) -> io::Result<IvfWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"DKIF")?;
+        file.write_all(&0u16.to_le_bytes())?; // version
+        file.write_all(&IVF_HEADER_SIZE.to_le_bytes())?;
+        file.write_all(fourcc)?;
+        // Width and height are left to the bitstream, they may change with every keyframe.
+        file.write_all(&0u16.to_le_bytes())?;
+        file.write_all(&0u16.to_le_bytes())?;
+        // Time base of the frame timestamps, 1 / clock rate.
+        file.write_all(&clock_rate.to_le_bytes())?;
+        file.write_all(&1u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?; // frame count, set by finish()
+        file.write_all(&0u32.to_le_bytes())?; // unused
+        Ok(IvfWriter { file, frames: 0 })
+    }
+}
+
+impl Container for IvfWriter {
+    fn write_frame(&mut self, pts: u64, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&pts.to_le_bytes())?;
+        self.file.write_all(frame)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(IVF_FRAME_COUNT_OFFSET))?;
+        self.file.write_all(&self.frames.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Opus always runs at 48 kHz, whatever the input was.
+const OPUS_RATE: u64 = 48_000;
+/// Samples decoders drop at the start: the lookahead of libopus, which browsers encode with, as
+/// RFC 7845 recommends for streams whose encoder delay is unknown otherwise.
+const OPUS_PRE_SKIP: u16 = 312;
+/// Samples of the frames filling gaps.
+const OPUS_GAP_FRAME: u64 = 960;
+/// A 20 ms frame without data, which decoders conceal like a lost one.
+const OPUS_EMPTY_FRAME: [u8; 1] = [0xf8];
+/// Header type flags of Ogg pages.
+const OGG_FIRST_PAGE: u8 = 0x02;
+const OGG_LAST_PAGE: u8 = 0x04;
+/// The only logical stream of every file.
+const OGG_SERIAL: u32 = 1;
+
+/// Ogg Opus file (RFC 7845), one packet per page.
+///
+/// Ogg has no timestamps, a packet is played after the ones before it. Gaps in the presentation
+/// times, from loss or from the track being muted, are filled with empty frames so that the audio
+/// stays in sync with the video recorded alongside.
+pub struct OggOpusWriter {
+    file: BufWriter<File>,
+    sequence: u32,
+    /// Samples written so far, the granule position of the last page.
+    granule: u64,
+    /// The last packet is held back to be written with the end of stream flag.
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl OggOpusWriter {
+    pub fn create(path: &Path, channels: u8) -> io::Result<OggOpusWriter> {
+        let mut writer = OggOpusWriter {
+            file: BufWriter::new(File::create(path)?),
+            sequence: 0,
+            granule: 0,
+            pending: None,
+        };
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(channels);
+        head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+        head.extend_from_slice(&(OPUS_RATE as u32).to_le_bytes());
+        head.extend_from_slice(&0u16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family: mono or stereo
+        writer.write_page(&head, OGG_FIRST_PAGE, 0)?;
+
+        let vendor = env!("CARGO_PKG_NAME").as_bytes();
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes()); // user comments
+        writer.write_page(&tags, 0, 0)?;
+        Ok(writer)
+    }
+
+    fn write_packet(&mut self, packet: &[u8], samples: u64) -> io::Result<()> {
+        if let Some((pending, granule)) = self.pending.take() {
+            self.write_page(&pending, 0, granule)?;
+        }
+        self.granule += samples;
+        self.pending = Some((packet.to_vec(), self.granule));
+        Ok(())
+    }
+
+    fn write_page(&mut self, packet: &[u8], header_type: u8, granule: u64) -> io::Result<()> {
+        // A packet is laced in segments of 255 bytes, ended by a shorter one.
+        let segments = packet.len() / 255 + 1;
+        if segments > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "packet doesn't fit in an Ogg page",
+            ));
+        }
+        let mut page = Vec::with_capacity(27 + segments + packet.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(header_type);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&OGG_SERIAL.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes()); // checksum, computed below
+        page.push(segments as u8);
+        page.extend(std::iter::repeat_n(255, segments - 1));
+        page.push((packet.len() % 255) as u8);
+        page.extend_from_slice(packet);
+        let checksum = ogg_crc(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        self.file.write_all(&page)?;
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+impl Container for OggOpusWriter {
+    fn write_frame(&mut self, pts: u64, frame: &[u8]) -> io::Result<()> {
+        while pts >= self.granule + OPUS_GAP_FRAME {
+            self.write_packet(&OPUS_EMPTY_FRAME, OPUS_GAP_FRAME)?;
+        }
+        let samples = opus_samples(frame).unwrap_or(OPUS_GAP_FRAME);
+        self.write_packet(frame, samples)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some((pending, granule)) = self.pending.take() {
+            self.write_page(&pending, OGG_LAST_PAGE, granule)?;
+        }
+        self.file.flush()
+    }
+}
+
+/// Samples at 48 kHz in an Opus packet, from its TOC byte (RFC 6716, section 3.1).
+fn opus_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame_samples = match config {
+        // SILK: 10, 20, 40 and 60 ms
+        0..=11 => [480, 960, 1920, 2880][usize::from(config % 4)],
+        // Hybrid: 10 and 20 ms
+        12..=15 => [480, 960][usize::from(config % 2)],
+        // CELT: 2.5, 5, 10 and 20 ms
+        _ => [120, 240, 480, 960][usize::from(config % 4)],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => u64::from(*packet.get(1)? & 0x3f),
+    };
+    Some(frame_samples * frames)
+}
+
+const OGG_CRC_TABLE: [u32; 256] = ogg_crc_table();
+
+/// CRC-32 of Ogg pages: polynomial 0x04c11db7, no reflection, no final xor.
+const fn ogg_crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ OGG_CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("signal_server_{}_{}", std::process::id(), name))
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_ivf_files() {
+        let path = temp_path("test.ivf");
+        let mut writer = IvfWriter::create(&path, b"VP80", 90000).unwrap();
+        writer.write_frame(0, &[1, 2, 3]).unwrap();
+        writer.write_frame(3000, &[4]).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        #[rustfmt::skip]
+        let header = [
+            b'D', b'K', b'I', b'F',
+            0, 0, // version
+            32, 0, // header size
+            b'V', b'P', b'8', b'0',
+            0, 0, 0, 0, // width and height
+            0x90, 0x5f, 0x01, 0x00, // 90000
+            1, 0, 0, 0,
+            2, 0, 0, 0, // frames
+            0, 0, 0, 0,
+        ];
+        assert_eq!(data[..32], header);
+        #[rustfmt::skip]
+        let frames = [
+            3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+            1, 0, 0, 0, 0xb8, 0x0b, 0, 0, 0, 0, 0, 0, 4,
+        ];
+        assert_eq!(data[32..], frames);
+    }
+
+    #[test]
+    fn computes_the_ogg_crc() {
+        assert_eq!(ogg_crc(b""), 0);
+        assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn writes_ogg_opus_files() {
+        let path = temp_path("test.ogg");
+        let mut writer = OggOpusWriter::create(&path, 2).unwrap();
+        writer.write_frame(0, &[0xfc, 0xaa]).unwrap();
+        // Two 20 ms frames went missing.
+        writer.write_frame(2880, &[0xfc, 0xbb]).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut pages = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            assert_eq!(&data[offset..offset + 4], b"OggS");
+            let segments = usize::from(data[offset + 26]);
+            let size: usize = data[offset + 27..offset + 27 + segments]
+                .iter()
+                .map(|&size| usize::from(size))
+                .sum();
+            let end = offset + 27 + segments + size;
+            let mut page = data[offset..end].to_vec();
+            page[22..26].fill(0);
+            assert_eq!(
+                ogg_crc(&page),
+                u32_at(&data, offset + 22),
+                "page {}",
+                pages.len()
+            );
+            assert_eq!(u32_at(&data, offset + 18), pages.len() as u32);
+            pages.push((
+                data[offset + 5],
+                u64_at(&data, offset + 6),
+                data[offset + 27 + segments..end].to_vec(),
+            ));
+            offset = end;
+        }
+
+        let (header_type, granule, head) = &pages[0];
+        assert_eq!((*header_type, *granule), (OGG_FIRST_PAGE, 0));
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!((head[8], head[9]), (1, 2));
+        assert_eq!(u16_at(head, 10), 312);
+        assert_eq!(u32_at(head, 12), 48000);
+        assert_eq!(&pages[1].2[..8], b"OpusTags");
+        let packets: Vec<_> = pages[2..]
+            .iter()
+            .map(|(header_type, granule, packet)| (*header_type, *granule, packet.as_slice()))
+            .collect();
+        assert_eq!(
+            packets,
+            [
+                (0, 960, &[0xfc, 0xaa][..]),
+                (0, 1920, &OPUS_EMPTY_FRAME[..]),
+                (0, 2880, &OPUS_EMPTY_FRAME[..]),
+                (OGG_LAST_PAGE, 3840, &[0xfc, 0xbb][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_opus_samples() {
+        let cases: [(&[u8], Option<u64>); 10] = [
+            (&[0x08], Some(960)),        // SILK 20 ms
+            (&[0x18], Some(2880)),       // SILK 60 ms
+            (&[0x70], Some(480)),        // hybrid 10 ms
+            (&[0x78], Some(960)),        // hybrid 20 ms
+            (&[0xe0], Some(120)),        // CELT 2.5 ms
+            (&[0xf8], Some(960)),        // CELT 20 ms
+            (&[0xf9], Some(1920)),       // two frames of the same size
+            (&[0xfb, 0x83], Some(2880)), // three frames, VBR
+            (&[0xfb], None),             // frame count missing
+            (&[], None),
+        ];
+        for (packet, samples) in cases {
+            assert_eq!(opus_samples(packet), samples, "{:02x?}", packet);
+        }
+    }
+}
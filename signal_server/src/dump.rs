@@ -0,0 +1,239 @@
+//! Debug dumps of the packets received on a peer connection: the RTP of the tracks it publishes
+//! and the RTCP it sends about the tracks it subscribes to, with their arrival times.
+//!
+//! Dumps are written in the rtpdump format of rtptools, which Wireshark opens as is. Next to
+//! `<name>.rtpdump`, `<name>.json` lists the layers the RTP belongs to, by SSRC, so that
+//! `replay` can publish the dump into a group again.
+use crate::role::Role;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tracing::warn;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::util::Marshal;
+
+/// First line of every rtpdump file, followed by the address the packets were sent from.
+const RTPDUMP_MAGIC: &str = "#!rtpplay1.0";
+/// Size of the binary file header following the first line.
+const RTPDUMP_HEADER_SIZE: usize = 16;
+/// Size of the header of every packet record.
+const RTPDUMP_RECORD_HEADER_SIZE: usize = 8;
+/// Dumps are flushed this often, so that they can be read while the peer connection is alive.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A layer published on the dumped peer connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpedLayer {
+    pub ssrc: u32,
+    pub track_id: String,
+    /// `audio` or `video`.
+    pub kind: String,
+    /// Empty for tracks without simulcast.
+    pub rid: String,
+    /// Role of the publisher, deciding who receives the track.
+    pub role: Role,
+    pub mime_type: String,
+    pub clock_rate: u32,
+    pub channels: u16,
+    pub sdp_fmtp_line: String,
+    /// Header extension carrying the audio levels, see `speaker`.
+    pub audio_level_extension: Option<u8>,
+}
+
+impl DumpedLayer {
+    pub fn codec(&self) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: self.mime_type.clone(),
+            clock_rate: self.clock_rate,
+            channels: self.channels,
+            sdp_fmtp_line: self.sdp_fmtp_line.clone(),
+            rtcp_feedback: vec![],
+        }
+    }
+}
+
+enum Record {
+    Layer(DumpedLayer),
+    /// Arrival time since the start of the dump, whether the packet is RTCP, the packet.
+    Packet(Duration, bool, Vec<u8>),
+}
+
+/// Writes the dump of one peer connection from a thread of its own, until dropped.
+#[derive(Debug)]
+pub struct RtpDump {
+    path: PathBuf,
+    start: Instant,
+    records: mpsc::Sender<Record>,
+}
+
+impl RtpDump {
+    /// Starts dumping into `<directory>/<name>.rtpdump` and `<directory>/<name>.json`.
+    pub fn create(directory: &Path, name: &str) -> io::Result<RtpDump> {
+        std::fs::create_dir_all(directory)?;
+        let path = directory.join(format!("{}.rtpdump", name));
+        let mut file = BufWriter::new(File::create(&path)?);
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // The address the packets came from is not kept, peers may change it anyway.
+        writeln!(file, "{} 0.0.0.0/0", RTPDUMP_MAGIC)?;
+        file.write_all(&(since_epoch.as_secs() as u32).to_be_bytes())?;
+        file.write_all(&since_epoch.subsec_micros().to_be_bytes())?;
+        file.write_all(&0u32.to_be_bytes())?; // source address
+        file.write_all(&0u16.to_be_bytes())?; // source port
+        file.write_all(&0u16.to_be_bytes())?; // padding
+
+        let (records, receiver) = mpsc::channel();
+        let mut writer = DumpWriter {
+            file,
+            layers_path: path.with_extension("json"),
+            layers: vec![],
+        };
+        writer.write_layers()?;
+        let thread_path = path.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = writer.run(receiver) {
+                warn!("Error writing RTP dump {}: {}", thread_path.display(), err);
+            }
+        });
+        Ok(RtpDump {
+            path,
+            start: Instant::now(),
+            records,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a layer whose RTP is dumped from now on.
+    pub fn add_layer(&self, layer: DumpedLayer) {
+        let _ = self.records.send(Record::Layer(layer));
+    }
+
+    /// Dumps an RTP packet which just arrived.
+    pub fn rtp(&self, packet: &webrtc::rtp::packet::Packet) {
+        if let Ok(data) = packet.marshal() {
+            self.send_packet(false, data.to_vec());
+        }
+    }
+
+    /// Dumps RTCP packets which just arrived together.
+    pub fn rtcp(&self, packets: &[Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>]) {
+        if let Ok(data) = webrtc::rtcp::packet::marshal(packets) {
+            self.send_packet(true, data.to_vec());
+        }
+    }
+
+    fn send_packet(&self, rtcp: bool, data: Vec<u8>) {
+        let _ = self
+            .records
+            .send(Record::Packet(self.start.elapsed(), rtcp, data));
+    }
+}
+
+struct DumpWriter {
+    file: BufWriter<File>,
+    layers_path: PathBuf,
+    layers: Vec<DumpedLayer>,
+}
+
+impl DumpWriter {
+    fn run(&mut self, records: mpsc::Receiver<Record>) -> io::Result<()> {
+        let mut flushed = Instant::now();
+        loop {
+            let record = match records.recv_timeout(FLUSH_INTERVAL) {
+                Ok(record) => Some(record),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            match record {
+                None => {}
+                Some(Record::Layer(layer)) => {
+                    self.layers.push(layer);
+                    self.write_layers()?;
+                }
+                Some(Record::Packet(arrival, rtcp, data)) => {
+                    let length =
+                        u16::try_from(RTPDUMP_RECORD_HEADER_SIZE + data.len()).map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidData, "packet too big")
+                        })?;
+                    // The length of the packet as received, only set for RTP.
+                    let packet_length = if rtcp { 0 } else { data.len() as u16 };
+                    self.file.write_all(&length.to_be_bytes())?;
+                    self.file.write_all(&packet_length.to_be_bytes())?;
+                    self.file
+                        .write_all(&(arrival.as_millis() as u32).to_be_bytes())?;
+                    self.file.write_all(&data)?;
+                }
+            }
+            if flushed.elapsed() >= FLUSH_INTERVAL {
+                self.file.flush()?;
+                flushed = Instant::now();
+            }
+        }
+        self.file.flush()
+    }
+
+    fn write_layers(&self) -> io::Result<()> {
+        std::fs::write(&self.layers_path, serde_json::to_vec_pretty(&self.layers)?)
+    }
+}
+
+/// A packet read back from a dump.
+pub struct DumpedPacket {
+    /// Arrival time since the start of the dump, to the millisecond.
+    pub arrival: Duration,
+    pub rtcp: bool,
+    pub data: Vec<u8>,
+}
+
+/// Reads a dump written by `RtpDump`, or any other rtpdump file with its layers described.
+pub struct DumpReader {
+    file: BufReader<tokio::fs::File>,
+}
+
+impl DumpReader {
+    /// Opens `path` and reads the layers described next to it.
+    pub async fn open(path: &Path) -> io::Result<(DumpReader, Vec<DumpedLayer>)> {
+        let layers = tokio::fs::read(path.with_extension("json")).await?;
+        let layers = serde_json::from_slice(&layers)?;
+        let mut file = BufReader::new(tokio::fs::File::open(path).await?);
+        let mut line = String::new();
+        file.read_line(&mut line).await?;
+        if !line.starts_with(RTPDUMP_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an rtpdump file",
+            ));
+        }
+        let mut header = [0; RTPDUMP_HEADER_SIZE];
+        file.read_exact(&mut header).await?;
+        Ok((DumpReader { file }, layers))
+    }
+
+    /// The next packet, `None` at the end of the dump.
+    pub async fn next(&mut self) -> io::Result<Option<DumpedPacket>> {
+        let mut header = [0; RTPDUMP_RECORD_HEADER_SIZE];
+        match self.file.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let length = usize::from(u16::from_be_bytes([header[0], header[1]]));
+        let packet_length = u16::from_be_bytes([header[2], header[3]]);
+        let arrival = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let mut data = vec![0; length.saturating_sub(RTPDUMP_RECORD_HEADER_SIZE)];
+        self.file.read_exact(&mut data).await?;
+        Ok(Some(DumpedPacket {
+            arrival: Duration::from_millis(u64::from(arrival)),
+            rtcp: packet_length == 0,
+            data,
+        }))
+    }
+}
@@ -0,0 +1,940 @@
+//! Fan-out of published tracks to their subscribers.
+//!
+//! A published `Track` is made of one layer per simulcast encoding, identified by its rid, or of
+//! a single layer with an empty rid when the publisher doesn't simulcast. Every subscriber gets
+//! its own `DownTrack` forwarding one layer at a time. Video starts and switches layers on
+//! keyframes only, requested from the publisher while a subscriber waits for one. Sequence
+//! numbers and timestamps are rewritten so that the subscriber sees one continuous stream. SSRC
+//! and payload type are set by the `TrackLocalStaticRTP` binding of the subscriber.
+//!
+//! The latest video packets of every layer are kept once per track, whatever the number of
+//! subscribers, to answer their NACKs.
+//!
+//! Recorders subscribe the same way, their `DownTrack` hands the packets over to a channel rather
+//! than to a peer connection.
+use crate::bwe::{LayerAllocation, LayerReason};
+use crate::keyframe::{is_keyframe, KeyframeRequester};
+use crate::protocol::{send_message, ClientSender, ServerMessage};
+use crate::role::Role;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter};
+use webrtc::Error;
+
+/// Interval over which the bitrate of a layer is measured.
+const BITRATE_INTERVAL: Duration = Duration::from_secs(1);
+/// A layer without packets for this long is taken for stopped by the publisher.
+const LAYER_TIMEOUT: Duration = Duration::from_secs(3);
+/// Video packets kept per layer for retransmissions, a power of two so that the slot of a
+/// sequence number survives its wrap around.
+const RETRANSMISSION_BUFFER_SIZE: usize = 512;
+/// Layer switches remembered per subscriber to map NACKed sequence numbers back to the layers.
+const MAX_SEGMENTS: usize = 8;
+
+#[derive(Debug)]
+pub struct Track {
+    id: String,
+    /// Id of the `WebRTCConnection` the track is published by.
+    publisher_id: String,
+    /// Identity of the client publishing the track, see `Client::identity`.
+    participant: String,
+    kind: RTPCodecType,
+    codec: RTCRtpCodecCapability,
+    // Role of the client publishing the track.
+    role: Role,
+    /// Rids of the simulcast layers announced by the publisher, in the order it declared them,
+    /// empty without simulcast.
+    rids: Vec<String>,
+    /// Layers the publisher is sending at the moment.
+    layers: Mutex<Vec<Layer>>,
+    keyframes: Arc<KeyframeRequester>,
+    /// Set by the publisher to stop forwarding the track to anyone.
+    muted: AtomicBool,
+    /// Keyed by the id of the subscribing `WebRTCConnection`.
+    down_tracks: Mutex<HashMap<String, Arc<DownTrack>>>,
+}
+
+#[derive(Debug)]
+struct Layer {
+    rid: String,
+    ssrc: u32,
+    /// Bits per second over the last measurement interval, zero until measured.
+    bitrate: u64,
+    bytes: u64,
+    since: Instant,
+    /// When the latest packet arrived.
+    received: Instant,
+    /// Latest packets, indexed by sequence number. Empty for audio, which isn't retransmitted.
+    buffer: Vec<Option<Packet>>,
+}
+
+impl Layer {
+    fn new(rid: &str, ssrc: u32, buffer_size: usize) -> Layer {
+        Layer {
+            rid: rid.to_owned(),
+            ssrc,
+            bitrate: 0,
+            bytes: 0,
+            since: Instant::now(),
+            received: Instant::now(),
+            buffer: vec![None; buffer_size],
+        }
+    }
+
+    fn record(&mut self, packet: &Packet) {
+        // A layer coming back is measured from scratch.
+        if self.is_stopped() {
+            self.bitrate = 0;
+            self.bytes = 0;
+            self.since = Instant::now();
+        }
+        self.received = Instant::now();
+        self.bytes += packet.payload.len() as u64;
+        let elapsed = self.since.elapsed();
+        if elapsed >= BITRATE_INTERVAL {
+            self.bitrate = (self.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            self.bytes = 0;
+            self.since = Instant::now();
+        }
+        if !self.buffer.is_empty() {
+            let slot = packet_slot(packet.header.sequence_number);
+            self.buffer[slot] = Some(packet.clone());
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.received.elapsed() >= LAYER_TIMEOUT
+    }
+
+    fn buffered(&self, sequence_number: u16) -> Option<&Packet> {
+        self.buffer
+            .get(packet_slot(sequence_number))?
+            .as_ref()
+            .filter(|packet| packet.header.sequence_number == sequence_number)
+    }
+}
+
+fn packet_slot(sequence_number: u16) -> usize {
+    usize::from(sequence_number) % RETRANSMISSION_BUFFER_SIZE
+}
+
+/// Rids and bitrates of the `layers` that aren't stopped, from the lowest to the highest. Until
+/// all of them are measured, the layers are taken to be declared in `rids` from the lowest up, as
+/// browsers do.
+fn rank_layers(layers: &[Layer], rids: &[String]) -> Vec<(String, u64)> {
+    let mut ranked: Vec<_> = layers
+        .iter()
+        .filter(|layer| !layer.is_stopped())
+        .map(|layer| (layer.rid.clone(), layer.bitrate))
+        .collect();
+    if ranked.iter().all(|(_, bitrate)| *bitrate > 0) {
+        ranked.sort_by_key(|(_, bitrate)| *bitrate);
+    } else {
+        ranked.sort_by_key(|(rid, _)| rids.iter().position(|declared| declared == rid));
+    }
+    ranked
+}
+
+impl Track {
+    pub fn new(
+        id: String,
+        publisher_id: String,
+        role: Role,
+        kind: RTPCodecType,
+        codec: RTCRtpCodecCapability,
+        mut rids: Vec<String>,
+        keyframes: Arc<KeyframeRequester>,
+    ) -> Track {
+        rids.retain(|rid| !rid.is_empty());
+        Track {
+            id,
+            participant: publisher_id.clone(),
+            publisher_id,
+            kind,
+            codec,
+            role,
+            rids,
+            layers: Mutex::new(vec![]),
+            keyframes,
+            muted: AtomicBool::new(false),
+            down_tracks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the identity of the publishing client, the publisher id unless set.
+    pub fn with_participant(mut self, participant: String) -> Track {
+        self.participant = participant;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn kind(&self) -> RTPCodecType {
+        self.kind
+    }
+
+    pub fn codec(&self) -> &RTCRtpCodecCapability {
+        &self.codec
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn publisher_id(&self) -> &str {
+        &self.publisher_id
+    }
+
+    pub fn participant(&self) -> &str {
+        &self.participant
+    }
+
+    pub fn rids(&self) -> &[String] {
+        &self.rids
+    }
+
+    pub fn is_simulcast(&self) -> bool {
+        !self.rids.is_empty()
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Stops or resumes forwarding the track, returns whether that changed anything. Subscribers
+    /// pick the track up again from its next keyframe.
+    pub fn set_muted(&self, muted: bool) -> bool {
+        if self.muted.swap(muted, Ordering::Relaxed) == muted {
+            return false;
+        }
+        if !muted {
+            for down_track in self.down_tracks.lock().unwrap().values() {
+                down_track.state.lock().unwrap().resync = true;
+            }
+        }
+        true
+    }
+
+    /// Starts forwarding layer `rid`, received on `ssrc`. Returns false, ignoring the layer, when
+    /// the track has a layer `rid` already: packets of both would end up in the same stream.
+    pub fn add_layer(&self, rid: &str, ssrc: u32) -> bool {
+        let buffer_size = match self.kind {
+            RTPCodecType::Video => RETRANSMISSION_BUFFER_SIZE,
+            _ => 0,
+        };
+        let mut layers = self.layers.lock().unwrap();
+        if layers.iter().any(|layer| layer.rid == rid) {
+            return false;
+        }
+        layers.push(Layer::new(rid, ssrc, buffer_size));
+        true
+    }
+
+    /// SSRCs of the layers being received.
+    pub fn ssrcs(&self) -> Vec<u32> {
+        let layers = self.layers.lock().unwrap();
+        layers.iter().map(|layer| layer.ssrc).collect()
+    }
+
+    /// Rids of the layers being received and their bitrate, from the lowest to the highest, see
+    /// `rank_layers`.
+    pub fn layer_bitrates(&self) -> Vec<(String, u64)> {
+        rank_layers(&self.layers.lock().unwrap(), &self.rids)
+    }
+
+    /// Stops forwarding layer `rid` and returns how many layers are left.
+    pub fn remove_layer(&self, rid: &str) -> usize {
+        let mut layers = self.layers.lock().unwrap();
+        layers.retain(|layer| layer.rid != rid);
+        layers.len()
+    }
+
+    /// Forwards a packet of layer `rid` to every subscriber forwarding that layer.
+    pub async fn forward(&self, rid: &str, packet: &Packet) {
+        if self.is_muted() {
+            return;
+        }
+        let (active, default_layer) = {
+            let mut layers = self.layers.lock().unwrap();
+            if let Some(layer) = layers.iter_mut().find(|layer| layer.rid == rid) {
+                layer.record(packet);
+            }
+            let active: Vec<String> = rank_layers(&layers, &self.rids)
+                .into_iter()
+                .map(|(rid, _)| rid)
+                .collect();
+            // Subscribers that didn't pick a layer get the best one.
+            let default_layer = active.last().cloned();
+            (active, default_layer)
+        };
+        let keyframe =
+            self.kind == RTPCodecType::Video && is_keyframe(&self.codec.mime_type, &packet.payload);
+        let down_tracks: Vec<_> = self.down_tracks.lock().unwrap().values().cloned().collect();
+        let mut waiting = false;
+        for down_track in down_tracks {
+            let target = down_track.target(&active, default_layer.as_deref());
+            waiting |= down_track.write(rid, target, packet, keyframe).await;
+        }
+        if waiting {
+            self.request_keyframe(rid).await;
+        }
+    }
+
+    /// Sends the packets `down_track` reported lost with a NACK again, as far as they are still
+    /// buffered.
+    pub async fn retransmit(&self, down_track: &DownTrack, sequence_numbers: &[u16]) {
+        for &sequence_number in sequence_numbers {
+            let segment = match down_track.segment_of(sequence_number) {
+                Some(segment) => segment,
+                None => continue,
+            };
+            let original = sequence_number.wrapping_sub(segment.seq_offset);
+            let packet = self
+                .layers
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|layer| layer.rid == segment.rid)
+                .and_then(|layer| layer.buffered(original))
+                .map(|packet| segment.rewrite(packet));
+            match packet {
+                Some(packet) => down_track.send(&packet).await,
+                None => debug!(
+                    "Track {} can't retransmit packet {}, it isn't buffered anymore",
+                    self.id, sequence_number
+                ),
+            }
+        }
+    }
+
+    /// Bits per second the publisher should send at most for every subscriber to keep up, unknown
+    /// without feedback from any of them. Simulcast tracks adapt to each subscriber by switching
+    /// layers and are never capped.
+    pub fn bitrate_hint(&self) -> Option<u64> {
+        if self.is_simulcast() {
+            return None;
+        }
+        let down_tracks = self.down_tracks.lock().unwrap();
+        down_tracks
+            .values()
+            .filter_map(|down_track| down_track.state.lock().unwrap().budget)
+            .min()
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.down_tracks.lock().unwrap().is_empty()
+    }
+
+    /// Asks the publisher for a keyframe of layer `rid`, rate limited by the publisher.
+    pub async fn request_keyframe(&self, rid: &str) {
+        if self.kind != RTPCodecType::Video {
+            return;
+        }
+        let ssrc = self
+            .layers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|layer| layer.rid == rid)
+            .map(|layer| layer.ssrc);
+        if let Some(ssrc) = ssrc {
+            self.keyframes.request(ssrc, &self.codec).await;
+        }
+    }
+
+    /// Creates the track forwarding this one to `subscriber_id`, who is told about layer
+    /// switches through `sender`, and the local track to add to its peer connection.
+    pub fn subscribe(
+        &self,
+        subscriber_id: &str,
+        sender: ClientSender,
+    ) -> (Arc<DownTrack>, Arc<TrackLocalStaticRTP>) {
+        let local = Arc::new(TrackLocalStaticRTP::new(
+            self.codec.clone(),
+            self.id.clone(),
+            self.publisher_id.clone(), // FIXME: mabye this should be changed so the stream of
+                                       // video-audio is different for each pair
+        ));
+        let output = Output::Peer {
+            local: local.clone(),
+            sender,
+        };
+        // Video waits for the last-N window of the subscriber to be worked out.
+        let down_track =
+            self.add_down_track(subscriber_id, output, self.kind == RTPCodecType::Video);
+        (down_track, local)
+    }
+
+    /// Creates the track forwarding this one to recorder `recorder_id`, which receives the
+    /// packets from the returned channel until it unsubscribes.
+    pub fn record(&self, recorder_id: &str) -> (Arc<DownTrack>, mpsc::UnboundedReceiver<Packet>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let down_track = self.add_down_track(recorder_id, Output::Recorder(sender), false);
+        (down_track, receiver)
+    }
+
+    fn add_down_track(
+        &self,
+        subscriber_id: &str,
+        output: Output,
+        outside_last_n: bool,
+    ) -> Arc<DownTrack> {
+        let down_track = Arc::new(DownTrack {
+            output,
+            track_id: self.id.clone(),
+            kind: self.kind,
+            clock_rate: self.codec.clock_rate,
+            simulcast: self.is_simulcast(),
+            state: Mutex::new(DownTrackState {
+                outside_last_n,
+                ..Default::default()
+            }),
+        });
+        self.down_tracks
+            .lock()
+            .unwrap()
+            .insert(subscriber_id.to_owned(), down_track.clone());
+        down_track
+    }
+
+    pub fn unsubscribe(&self, subscriber_id: &str) {
+        self.down_tracks.lock().unwrap().remove(subscriber_id);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerStats {
+    pub rid: String,
+    /// Bits per second received from the publisher.
+    pub bitrate: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownTrackStats {
+    pub track_id: String,
+    pub layers: Vec<LayerStats>,
+    /// Layer picked by the subscriber.
+    pub requested: Option<String>,
+    /// Layer picked from the bandwidth estimate.
+    pub allocated: Option<String>,
+    pub reason: LayerReason,
+    /// Bits per second the subscriber can take on this track.
+    pub budget: Option<u64>,
+    pub paused: bool,
+    /// Set while the track doesn't make it into the last-N window of the subscriber.
+    pub outside_last_n: bool,
+    /// Layer being forwarded, switches wait for a keyframe.
+    pub current: Option<String>,
+}
+
+/// A published track as forwarded to one subscriber.
+#[derive(Debug)]
+pub struct DownTrack {
+    output: Output,
+    track_id: String,
+    kind: RTPCodecType,
+    clock_rate: u32,
+    simulcast: bool,
+    state: Mutex<DownTrackState>,
+}
+
+/// Where a `DownTrack` sends its packets.
+#[derive(Debug)]
+enum Output {
+    /// To a subscriber's peer connection, the subscriber is told about layer switches.
+    Peer {
+        local: Arc<TrackLocalStaticRTP>,
+        sender: ClientSender,
+    },
+    /// To a recorder, see `recorder`.
+    Recorder(mpsc::UnboundedSender<Packet>),
+}
+
+#[derive(Debug, Default)]
+struct DownTrackState {
+    /// Layer picked by the subscriber, overriding the allocated one.
+    requested: Option<String>,
+    /// Layer fitting the bandwidth of the subscriber, the default one when unset.
+    allocation: LayerAllocation,
+    /// Bits per second the subscriber can take on this track, from its bandwidth estimate.
+    budget: Option<u64>,
+    /// Set by the subscriber to stop receiving the track.
+    paused: bool,
+    /// Set by the server while the track is left out of the video the subscriber gets.
+    outside_last_n: bool,
+    /// Set when the stream was interrupted, it goes on from the next keyframe like on a switch.
+    resync: bool,
+    /// Layers forwarded so far, the current one last, none until the first packet went out.
+    segments: VecDeque<Segment>,
+    /// Newest sequence number and timestamp sent, and when the last packet went out.
+    last_seq: u16,
+    last_ts: u32,
+    last_sent: Option<Instant>,
+}
+
+impl DownTrack {
+    /// Layer being forwarded, none until the first packet went out.
+    pub fn current_layer(&self) -> Option<String> {
+        self.state.lock().unwrap().current().map(str::to_owned)
+    }
+
+    /// Picks layer `rid` for the subscriber, or the default layer when `None`. The switch
+    /// happens on the next keyframe of that layer.
+    pub fn request_layer(&self, rid: Option<String>) {
+        self.state.lock().unwrap().requested = rid;
+    }
+
+    /// Picks the layer out of `layers` fitting `share` bits per second, see
+    /// `LayerAllocation::allocate`.
+    pub fn allocate(&self, layers: &[(String, u64)], share: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.budget = share;
+        let previous = state.allocation.rid.clone();
+        state.allocation.allocate(layers, share);
+        if state.allocation.rid != previous {
+            debug!(
+                "Track {} allocated layer {:?} for a share of {:?} bps: {:?}",
+                self.track_id, state.allocation.rid, share, state.allocation.reason
+            );
+        }
+    }
+
+    /// Whether forwarding is paused, by the subscriber or for being outside its last-N window.
+    pub fn is_paused(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.paused || state.outside_last_n
+    }
+
+    /// Stops or resumes forwarding to the subscriber, which picks the track up again from its next
+    /// keyframe.
+    pub fn set_paused(&self, paused: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused && !paused {
+            state.resync = true;
+        }
+        state.paused = paused;
+    }
+
+    /// Stops forwarding while the track is outside the last-N window of the subscriber, returns
+    /// whether that changed anything. Back inside, the track goes on from its next keyframe.
+    pub fn set_outside_last_n(&self, outside: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.outside_last_n == outside {
+            return false;
+        }
+        if !outside {
+            state.resync = true;
+        }
+        state.outside_last_n = outside;
+        true
+    }
+
+    /// Goes on from the next keyframe, which is requested from the publisher, e.g. after the
+    /// subscriber lost a frame for good.
+    pub fn resync(&self) {
+        self.state.lock().unwrap().resync = true;
+    }
+
+    /// Sets the bandwidth left for a track without simulcast, which can't adapt to it by itself.
+    pub fn set_budget(&self, budget: Option<u64>) {
+        self.state.lock().unwrap().budget = budget;
+    }
+
+    pub fn stats(&self, layers: Vec<(String, u64)>) -> DownTrackStats {
+        let state = self.state.lock().unwrap();
+        DownTrackStats {
+            track_id: self.track_id.clone(),
+            layers: layers
+                .into_iter()
+                .map(|(rid, bitrate)| LayerStats { rid, bitrate })
+                .collect(),
+            requested: state.requested.clone(),
+            allocated: state.allocation.rid.clone(),
+            reason: match state.requested {
+                Some(_) => LayerReason::Requested,
+                None => state.allocation.reason,
+            },
+            budget: state.budget,
+            paused: state.paused,
+            outside_last_n: state.outside_last_n,
+            current: state.current().map(str::to_owned),
+        }
+    }
+
+    /// Layer that should be forwarded out of the `active` ones.
+    fn target<'a>(&self, active: &'a [String], default_layer: Option<&'a str>) -> Option<&'a str> {
+        let state = self.state.lock().unwrap();
+        let find = |rid: &String| active.iter().find(|active| *active == rid);
+        state
+            .requested
+            .as_ref()
+            .and_then(find)
+            .or_else(|| state.allocation.rid.as_ref().and_then(find))
+            .map(String::as_str)
+            .or(default_layer)
+    }
+
+    /// Forwards `packet` of layer `rid` if that is the layer to forward. Returns whether the
+    /// subscriber is waiting for a keyframe of that layer.
+    async fn write(
+        &self,
+        rid: &str,
+        target: Option<&str>,
+        packet: &Packet,
+        keyframe: bool,
+    ) -> bool {
+        let (packet, switched) = {
+            let mut state = self.state.lock().unwrap();
+            if state.paused || state.outside_last_n {
+                return false;
+            }
+            let mut switched = false;
+            if state.resync || state.current() != Some(rid) {
+                if target != Some(rid) {
+                    return false;
+                }
+                // Video can only be decoded from a keyframe on.
+                if self.kind == RTPCodecType::Video && !keyframe {
+                    return true;
+                }
+                switched = state.current() != Some(rid);
+                state.resync = false;
+                state.switch_to(rid, packet, self.clock_rate);
+            }
+            (state.rewrite(packet), switched)
+        };
+        if switched && self.simulcast {
+            debug!("Track {} switched to layer {}", self.track_id, rid);
+            if let Output::Peer { sender, .. } = &self.output {
+                send_message(
+                    sender,
+                    &ServerMessage::LayerSwitched {
+                        track_id: self.track_id.clone(),
+                        rid: rid.to_owned(),
+                    },
+                );
+            }
+        }
+        self.send(&packet).await;
+        false
+    }
+
+    async fn send(&self, packet: &Packet) {
+        match &self.output {
+            Output::Peer { local, .. } => {
+                if let Err(err) = local.write_rtp(packet).await {
+                    // closed until the subscriber negotiated the track
+                    if Error::ErrClosedPipe != err {
+                        warn!("Error forwarding track {}: {}", self.track_id, err);
+                    }
+                }
+            }
+            // The recorder is done with the track when it hung up.
+            Output::Recorder(packets) => {
+                let _ = packets.send(packet.clone());
+            }
+        }
+    }
+
+    /// The segment outgoing `sequence_number` was sent in, unless it is too old or wasn't sent.
+    fn segment_of(&self, sequence_number: u16) -> Option<Segment> {
+        let state = self.state.lock().unwrap();
+        if state.last_sent.is_none() || state.last_seq.wrapping_sub(sequence_number) >= 0x8000 {
+            return None;
+        }
+        state
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| sequence_number.wrapping_sub(segment.first_seq) < 0x8000)
+            .cloned()
+    }
+}
+
+/// A stretch of the stream sent to a subscriber, forwarded from a single layer.
+#[derive(Debug, Clone)]
+struct Segment {
+    rid: String,
+    /// Sequence number the subscriber saw the first packet of the segment with.
+    first_seq: u16,
+    /// Added to the sequence numbers and timestamps of the layer.
+    seq_offset: u16,
+    ts_offset: u32,
+}
+
+impl Segment {
+    fn rewrite(&self, packet: &Packet) -> Packet {
+        let mut packet = packet.clone();
+        let header = &mut packet.header;
+        header.sequence_number = header.sequence_number.wrapping_add(self.seq_offset);
+        header.timestamp = header.timestamp.wrapping_add(self.ts_offset);
+        // Extension ids were negotiated with the publisher, they mean nothing to the subscriber.
+        header.extension = false;
+        header.extension_profile = 0;
+        header.extensions.clear();
+        packet
+    }
+}
+
+impl DownTrackState {
+    fn current(&self) -> Option<&str> {
+        self.segments.back().map(|segment| segment.rid.as_str())
+    }
+
+    /// Makes layer `rid` continue the stream sent so far, starting with `packet`.
+    fn switch_to(&mut self, rid: &str, packet: &Packet, clock_rate: u32) {
+        let (seq_offset, ts_offset) = match self.last_sent {
+            Some(last_sent) => {
+                // Right after the last packet sent, as much later as time went by since.
+                let elapsed = (last_sent.elapsed().as_secs_f64() * f64::from(clock_rate)) as u32;
+                (
+                    self.last_seq
+                        .wrapping_add(1)
+                        .wrapping_sub(packet.header.sequence_number),
+                    self.last_ts
+                        .wrapping_add(elapsed.max(1))
+                        .wrapping_sub(packet.header.timestamp),
+                )
+            }
+            None => (0, 0),
+        };
+        if self.segments.len() == MAX_SEGMENTS {
+            self.segments.pop_front();
+        }
+        self.segments.push_back(Segment {
+            rid: rid.to_owned(),
+            first_seq: packet.header.sequence_number.wrapping_add(seq_offset),
+            seq_offset,
+            ts_offset,
+        });
+    }
+
+    fn rewrite(&mut self, packet: &Packet) -> Packet {
+        let packet = match self.segments.back() {
+            Some(segment) => segment.rewrite(packet),
+            None => packet.clone(),
+        };
+        let header = &packet.header;
+        // Retransmissions and reordered packets must not move the stream back.
+        let newer = header.sequence_number.wrapping_sub(self.last_seq) < 0x8000;
+        if self.last_sent.is_none() || newer {
+            self.last_seq = header.sequence_number;
+            self.last_ts = header.timestamp;
+        }
+        self.last_sent = Some(Instant::now());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::api::media_engine::MIME_TYPE_VP8;
+    use webrtc::rtp::header::Header;
+
+    const KEYFRAME: [u8; 2] = [0x10, 0x00];
+    const DELTA_FRAME: [u8; 2] = [0x10, 0x01];
+
+    fn packet(sequence_number: u16, timestamp: u32, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            payload: payload.to_vec().into(),
+        }
+    }
+
+    fn video_track(rids: &[&str]) -> Track {
+        Track::new(
+            "track".to_owned(),
+            "publisher".to_owned(),
+            Role::Robot,
+            RTPCodecType::Video,
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_VP8.to_owned(),
+                clock_rate: 90000,
+                ..Default::default()
+            },
+            rids.iter().map(|rid| rid.to_string()).collect(),
+            Arc::new(KeyframeRequester::new(std::sync::Weak::new())),
+        )
+    }
+
+    fn sequence_numbers(packets: &mut mpsc::UnboundedReceiver<Packet>) -> Vec<u16> {
+        let mut sequence_numbers = vec![];
+        while let Ok(packet) = packets.try_recv() {
+            sequence_numbers.push(packet.header.sequence_number);
+        }
+        sequence_numbers
+    }
+
+    fn layer(rid: &str, bitrate: u64) -> Layer {
+        Layer {
+            bitrate,
+            ..Layer::new(rid, 0, 0)
+        }
+    }
+
+    #[test]
+    fn ranks_layers_in_declared_order_until_measured() {
+        let rids = ["q".to_owned(), "h".to_owned(), "f".to_owned()];
+        let ranked = |layers: &[Layer]| {
+            rank_layers(layers, &rids)
+                .into_iter()
+                .map(|(rid, _)| rid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ranked(&[layer("f", 0), layer("q", 0), layer("h", 0)]),
+            ["q", "h", "f"]
+        );
+        assert_eq!(
+            ranked(&[layer("f", 1_200_000), layer("q", 0), layer("h", 400_000)]),
+            ["q", "h", "f"]
+        );
+        // Measured bitrates win over the declared order.
+        assert_eq!(
+            ranked(&[
+                layer("f", 100_000),
+                layer("q", 150_000),
+                layer("h", 400_000)
+            ]),
+            ["f", "q", "h"]
+        );
+    }
+
+    #[test]
+    fn stopped_layers_are_left_out_and_measured_again() {
+        let rids = ["q".to_owned(), "h".to_owned()];
+        let mut stopped = layer("h", 500_000);
+        stopped.received -= LAYER_TIMEOUT;
+        assert_eq!(
+            rank_layers(&[layer("q", 150_000), stopped], &rids),
+            [("q".to_owned(), 150_000)]
+        );
+
+        let mut resumed = layer("h", 500_000);
+        resumed.received -= LAYER_TIMEOUT;
+        resumed.since -= LAYER_TIMEOUT;
+        resumed.record(&packet(0, 0, &KEYFRAME));
+        assert_eq!(resumed.bitrate, 0);
+        assert_eq!(resumed.bytes, KEYFRAME.len() as u64);
+        assert!(!resumed.is_stopped());
+    }
+
+    #[test]
+    fn segment_rewrite_wraps_around() {
+        let segment = Segment {
+            rid: "h".to_owned(),
+            first_seq: 0,
+            seq_offset: 3,
+            ts_offset: 1000,
+        };
+        let mut original = packet(65534, u32::MAX - 499, &KEYFRAME);
+        original.header.extension = true;
+        original.header.extension_profile = 0xbede;
+        let rewritten = segment.rewrite(&original);
+        assert_eq!(rewritten.header.sequence_number, 1);
+        assert_eq!(rewritten.header.timestamp, 500);
+        assert!(!rewritten.header.extension);
+        assert_eq!(rewritten.header.extension_profile, 0);
+        assert_eq!(rewritten.payload, original.payload);
+    }
+
+    #[test]
+    fn switches_continue_the_stream_across_wraparound() {
+        let mut state = DownTrackState::default();
+        state.switch_to("q", &packet(65534, 1000, &KEYFRAME), 90000);
+        let sent: Vec<u16> = [65534, 65535, 0]
+            .iter()
+            .map(|&seq| {
+                state
+                    .rewrite(&packet(seq, 1000, &DELTA_FRAME))
+                    .header
+                    .sequence_number
+            })
+            .collect();
+        assert_eq!(sent, [65534, 65535, 0]);
+
+        // The new layer numbers its packets on its own, they go on right after the last one sent.
+        let first = packet(40000, 7_000_000, &KEYFRAME);
+        state.switch_to("h", &first, 90000);
+        let rewritten = state.rewrite(&first);
+        assert_eq!(rewritten.header.sequence_number, 1);
+        assert!(rewritten.header.timestamp.wrapping_sub(1000) >= 1);
+        assert_eq!(
+            state
+                .rewrite(&packet(40001, 7_000_000, &DELTA_FRAME))
+                .header
+                .sequence_number,
+            2
+        );
+        assert_eq!(state.current(), Some("h"));
+
+        // Switching back maps the old layer onto the stream again, wrapping its numbers around.
+        state.switch_to("q", &packet(3, 4000, &KEYFRAME), 90000);
+        assert_eq!(state.segments.len(), 3);
+        let segment = state.segments.back().unwrap();
+        assert_eq!(segment.first_seq, 3);
+        assert_eq!(segment.seq_offset, 0);
+    }
+
+    #[test]
+    fn late_packets_dont_move_the_stream_back() {
+        let mut state = DownTrackState::default();
+        state.switch_to("", &packet(10, 0, &KEYFRAME), 90000);
+        state.rewrite(&packet(10, 0, &KEYFRAME));
+        state.rewrite(&packet(12, 3000, &DELTA_FRAME));
+        state.rewrite(&packet(11, 3000, &DELTA_FRAME));
+        assert_eq!((state.last_seq, state.last_ts), (12, 3000));
+    }
+
+    #[tokio::test]
+    async fn switches_layers_on_keyframes() {
+        let track = video_track(&["q", "h"]);
+        assert!(track.add_layer("q", 1));
+        assert!(track.add_layer("h", 2));
+        assert!(!track.add_layer("h", 3));
+        assert_eq!(track.ssrcs(), [1, 2]);
+        let (down_track, mut packets) = track.record("recorder");
+        down_track.request_layer(Some("q".to_owned()));
+
+        track.forward("q", &packet(65535, 0, &DELTA_FRAME)).await;
+        assert!(sequence_numbers(&mut packets).is_empty());
+        track.forward("q", &packet(0, 3000, &KEYFRAME)).await;
+        track.forward("h", &packet(500, 3000, &KEYFRAME)).await;
+        track.forward("q", &packet(1, 6000, &DELTA_FRAME)).await;
+        assert_eq!(sequence_numbers(&mut packets), [0, 1]);
+
+        // Layer q goes on until a keyframe of layer h arrives.
+        down_track.request_layer(Some("h".to_owned()));
+        track.forward("h", &packet(501, 6000, &DELTA_FRAME)).await;
+        track.forward("q", &packet(2, 9000, &DELTA_FRAME)).await;
+        track.forward("h", &packet(502, 9000, &KEYFRAME)).await;
+        track.forward("h", &packet(503, 12000, &DELTA_FRAME)).await;
+        assert_eq!(sequence_numbers(&mut packets), [2, 3, 4]);
+        assert_eq!(down_track.current_layer().as_deref(), Some("h"));
+
+        // NACKed sequence numbers map back to the layer and sequence number they came from.
+        let origin = |sequence_number: u16| {
+            down_track.segment_of(sequence_number).map(|segment| {
+                let original = sequence_number.wrapping_sub(segment.seq_offset);
+                (segment.rid, original)
+            })
+        };
+        assert_eq!(origin(2), Some(("q".to_owned(), 2)));
+        assert_eq!(origin(4), Some(("h".to_owned(), 503)));
+        assert_eq!(origin(5), None);
+    }
+}
@@ -1,8 +1,69 @@
-use crate::{ws, Clients, Groups, Result};
+use crate::protocol::{negotiate_version, PROTOCOL_VERSION};
+use crate::{ws, Clients, Groups, Result, ServerOptions};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+use warp::http::StatusCode;
 use warp::Reply;
 
-pub async fn ws_handler(ws: warp::ws::Ws, clients: Clients, groups: Groups) -> Result<impl Reply> {
-    println!("ws_handler");
+pub async fn ws_handler(
+    ws: warp::ws::Ws,
+    query: HashMap<String, String>,
+    origin: Option<String>,
+    clients: Clients,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    debug!("Websocket requested from origin {:?}", origin);
 
-    Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, clients, groups)))
+    // Browsers always send their origin, other clients are not subject to it.
+    if let Some(origin) = origin.filter(|origin| !options.allows_origin(origin)) {
+        warn!("Rejecting websocket from origin {}", origin);
+        return Ok(
+            warp::reply::with_status("origin not allowed", StatusCode::FORBIDDEN).into_response(),
+        );
+    }
+
+    // Without a token in the query the client has to present one in its hello message.
+    let claims = match (&options.authenticator, query.get("token")) {
+        (Some(authenticator), Some(token)) => match authenticator.verify(token) {
+            Ok(claims) => Some(claims),
+            Err(err) => {
+                warn!("Rejecting websocket with invalid token: {}", err);
+                return Ok(
+                    warp::reply::with_status("invalid token", StatusCode::UNAUTHORIZED)
+                        .into_response(),
+                );
+            }
+        },
+        _ => None,
+    };
+
+    let protocol_version = match query.get("protocol_version") {
+        Some(version) => match version.parse().ok().and_then(negotiate_version) {
+            Some(version) => version,
+            None => {
+                warn!("Rejecting websocket with protocol version {:?}", version);
+                return Ok(warp::reply::with_status(
+                    "unsupported protocol version",
+                    StatusCode::BAD_REQUEST,
+                )
+                .into_response());
+            }
+        },
+        None => PROTOCOL_VERSION,
+    };
+
+    Ok(ws
+        .on_upgrade(move |socket| {
+            ws::client_connection(
+                socket,
+                clients,
+                groups,
+                options,
+                claims,
+                protocol_version,
+                query.get("resume").cloned(),
+            )
+        })
+        .into_response())
 }
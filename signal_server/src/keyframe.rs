@@ -0,0 +1,251 @@
+//! Recognizes the RTP packets starting a keyframe, the only points at which a subscriber can
+//! start decoding a video stream, and asks publishers for one when a subscriber needs it.
+use std::collections::HashMap;
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use webrtc::api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::packet::Packet;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+/// Keyframes are requested at most this often per layer, however many subscribers wait for one.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sends keyframe requests to one publisher.
+#[derive(Debug)]
+pub struct KeyframeRequester {
+    pc: Weak<RTCPeerConnection>,
+    /// When a keyframe was last requested and the last FIR sequence number, per media SSRC.
+    requests: Mutex<HashMap<u32, (Instant, u8)>>,
+}
+
+impl KeyframeRequester {
+    pub fn new(pc: Weak<RTCPeerConnection>) -> KeyframeRequester {
+        KeyframeRequester {
+            pc,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Asks for a keyframe on `media_ssrc`, sent with `codec`, unless one was asked for
+    /// recently. FIR is only used when the publisher doesn't support PLI.
+    pub async fn request(&self, media_ssrc: u32, codec: &RTCRtpCodecCapability) {
+        let sequence_number = {
+            let mut requests = self.requests.lock().unwrap();
+            let sequence_number = match requests.get(&media_ssrc) {
+                Some((last, _)) if last.elapsed() < KEYFRAME_REQUEST_INTERVAL => return,
+                Some((_, sequence_number)) => sequence_number.wrapping_add(1),
+                None => 0,
+            };
+            requests.insert(media_ssrc, (Instant::now(), sequence_number));
+            sequence_number
+        };
+        let pc = match self.pc.upgrade() {
+            Some(pc) => pc,
+            None => return,
+        };
+        let supports = |typ: &str, parameter: &str| {
+            codec
+                .rtcp_feedback
+                .iter()
+                .any(|feedback| feedback.typ == typ && feedback.parameter == parameter)
+        };
+        let packet: Box<dyn Packet + Send + Sync> =
+            if !supports("nack", "pli") && supports("ccm", "fir") {
+                Box::new(FullIntraRequest {
+                    sender_ssrc: 0,
+                    media_ssrc,
+                    fir: vec![FirEntry {
+                        ssrc: media_ssrc,
+                        sequence_number,
+                    }],
+                })
+            } else {
+                Box::new(PictureLossIndication {
+                    sender_ssrc: 0,
+                    media_ssrc,
+                })
+            };
+        debug!("Requesting a keyframe on ssrc {}", media_ssrc);
+        if let Err(err) = pc.write_rtcp(&[packet]).await {
+            debug!(
+                "Error requesting a keyframe on ssrc {}: {}",
+                media_ssrc, err
+            );
+        }
+    }
+}
+
+/// Whether `payload`, carrying `mime_type`, starts a keyframe. Always false for codecs without
+/// keyframes, like audio.
+pub fn is_keyframe(mime_type: &str, payload: &[u8]) -> bool {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        is_vp8_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        is_vp9_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        is_h264_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+        is_av1_keyframe(payload)
+    } else {
+        false
+    }
+}
+
+/// RFC 7741: the first partition of a frame whose payload header has the inverse key frame
+/// flag cleared.
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    let descriptor = match payload.first() {
+        Some(descriptor) => *descriptor,
+        None => return false,
+    };
+    let start_of_partition = descriptor & 0x10 != 0;
+    let partition_id = descriptor & 0x07;
+    if !start_of_partition || partition_id != 0 {
+        return false;
+    }
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        let extensions = match payload.get(offset) {
+            Some(extensions) => *extensions,
+            None => return false,
+        };
+        offset += 1;
+        if extensions & 0x80 != 0 {
+            // picture id, 15 bits when its first bit is set
+            match payload.get(offset) {
+                Some(picture_id) if picture_id & 0x80 != 0 => offset += 2,
+                Some(_) => offset += 1,
+                None => return false,
+            }
+        }
+        if extensions & 0x40 != 0 {
+            offset += 1; // TL0PICIDX
+        }
+        if extensions & 0x30 != 0 {
+            offset += 1; // TID/Y/KEYIDX
+        }
+    }
+    matches!(payload.get(offset), Some(header) if header & 0x01 == 0)
+}
+
+/// VP9 payload descriptor: the start of a frame that is not inter-picture predicted.
+fn is_vp9_keyframe(payload: &[u8]) -> bool {
+    match payload.first() {
+        Some(descriptor) => descriptor & 0x40 == 0 && descriptor & 0x08 != 0,
+        None => false,
+    }
+}
+
+/// RFC 6184: an IDR slice or a sequence parameter set, on its own, aggregated in a STAP-A or
+/// starting a FU-A.
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    const IDR: u8 = 5;
+    const SPS: u8 = 7;
+    const STAP_A: u8 = 24;
+    const FU_A: u8 = 28;
+
+    let nal_type = match payload.first() {
+        Some(header) => header & 0x1f,
+        None => return false,
+    };
+    match nal_type {
+        IDR | SPS => true,
+        STAP_A => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                let nal_type = payload[offset + 2] & 0x1f;
+                if nal_type == IDR || nal_type == SPS {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        FU_A => match payload.get(1) {
+            Some(fu_header) => {
+                let start = fu_header & 0x80 != 0;
+                let nal_type = fu_header & 0x1f;
+                start && (nal_type == IDR || nal_type == SPS)
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// AV1 aggregation header: the first packet of a coded video sequence.
+fn is_av1_keyframe(payload: &[u8]) -> bool {
+    match payload.first() {
+        Some(header) => header & 0x08 != 0,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_keyframes() {
+        let cases: &[(&str, &[u8], bool)] = &[
+            // VP8: start of partition 0, then the inverse key frame flag of the payload header.
+            (MIME_TYPE_VP8, &[0x10, 0x00], true),
+            (MIME_TYPE_VP8, &[0x10, 0x01], false),
+            (MIME_TYPE_VP8, &[0x00, 0x00], false),
+            (MIME_TYPE_VP8, &[0x11, 0x00], false),
+            // VP8 with a 15 bit picture id, a TL0PICIDX and a KEYIDX to skip.
+            (
+                MIME_TYPE_VP8,
+                &[0x90, 0xd0, 0x81, 0x23, 0x07, 0x10, 0x00],
+                true,
+            ),
+            (
+                MIME_TYPE_VP8,
+                &[0x90, 0xd0, 0x81, 0x23, 0x07, 0x10, 0x01],
+                false,
+            ),
+            // VP8 with a 7 bit picture id.
+            (MIME_TYPE_VP8, &[0x90, 0x80, 0x23, 0x00], true),
+            (MIME_TYPE_VP8, &[0x90, 0x80, 0x23], false),
+            (MIME_TYPE_VP8, &[], false),
+            // VP9: start of a frame without inter-picture prediction.
+            (MIME_TYPE_VP9, &[0x08], true),
+            (MIME_TYPE_VP9, &[0x48], false),
+            (MIME_TYPE_VP9, &[0x00], false),
+            // H264: IDR and SPS, on their own, in a STAP-A or starting a FU-A.
+            (MIME_TYPE_H264, &[0x65], true),
+            (MIME_TYPE_H264, &[0x67], true),
+            (MIME_TYPE_H264, &[0x41], false),
+            (
+                MIME_TYPE_H264,
+                &[0x78, 0x00, 0x02, 0x09, 0x10, 0x00, 0x02, 0x67, 0x42],
+                true,
+            ),
+            (MIME_TYPE_H264, &[0x78, 0x00, 0x02, 0x41, 0x9a], false),
+            (MIME_TYPE_H264, &[0x7c, 0x85], true),
+            (MIME_TYPE_H264, &[0x7c, 0x05], false),
+            (MIME_TYPE_H264, &[0x7c, 0x81], false),
+            (MIME_TYPE_H264, &[0x7c], false),
+            // AV1: the new coded video sequence flag of the aggregation header.
+            (MIME_TYPE_AV1, &[0x08], true),
+            (MIME_TYPE_AV1, &[0x10], false),
+            // Mime types are case insensitive, audio has no keyframes.
+            ("video/vp8", &[0x10, 0x00], true),
+            ("audio/opus", &[0x10, 0x00], false),
+        ];
+        for (mime_type, payload, keyframe) in cases {
+            assert_eq!(
+                is_keyframe(mime_type, payload),
+                *keyframe,
+                "{} {:02x?}",
+                mime_type,
+                payload
+            );
+        }
+    }
+}
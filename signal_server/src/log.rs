@@ -7,7 +7,8 @@ pub struct StdoutLogger;
 
 impl<S: Subscriber> Layer<S> for StdoutLogger {}
 
-pub fn start_logger() {
+/// Starts logging with the `RUST_LOG` filter, falling back to `default_filter`.
+pub fn start_logger(default_filter: &str) {
     // tracing::subscriber::set_global_default(file_logger)
     //   .expect("setting file_logger failed");
     let fmt_layer = fmt::layer()
@@ -16,7 +17,7 @@ pub fn start_logger() {
         .with_file(true);
 
     let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("debug"))
+        .or_else(|_| EnvFilter::try_new(default_filter))
         .unwrap();
 
     let stdout_logger = StdoutLogger;
@@ -1,42 +1,131 @@
-use warp::{ws::Message, Filter, Rejection};
+use warp::{Filter, Rejection};
 
-use clap::{arg, Command};
-use std::net::SocketAddr;
+use clap::{arg, value_parser, ArgMatches, Command};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{collections::HashMap, convert::Infallible, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::Mutex;
 
 use log::start_logger;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
+mod admin;
+mod auth;
+mod bwe;
+mod codecs;
+mod config;
+mod container;
+mod dump;
+mod forward;
 mod handler;
+mod keyframe;
 mod log;
+mod protocol;
+mod recorder;
+mod replay;
+mod role;
+mod speaker;
+mod turn;
 mod webrtc;
+mod whip;
 mod ws;
-use crate::webrtc::{Track, WebRTCConnection};
+use crate::auth::{Authenticator, Claims};
+use crate::codecs::GroupCodecs;
+use crate::config::Config;
+use crate::forward::Track;
+use crate::protocol::{send_message, ClientSender, IceServer, ServerMessage};
+use crate::recorder::Recorder;
+use crate::role::Role;
+use crate::speaker::SpeakerDetector;
+use crate::turn::TurnServer;
+use crate::webrtc::{LastN, WebRTCConnection};
+use ::webrtc::api::API;
+use ::webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use ::webrtc::ice_transport::ice_server::RTCIceServer;
+use ::webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
 
 #[derive(Debug, Clone)]
 pub struct Group {
     pub clients: Arc<Mutex<Vec<Arc<Mutex<Client>>>>>,
+    /// Codecs every member has to use, pinned by the first offer sending each kind.
+    pub codecs: Arc<Mutex<GroupCodecs>>,
+    /// Fed by the audio tracks published into the group, see `speaker::spawn_detection`.
+    pub speakers: Arc<std::sync::Mutex<SpeakerDetector>>,
+    /// Records the tracks of the group while set.
+    pub recorder: Option<Arc<Recorder>>,
+    /// Tracks replayed from RTP dumps, published by none of the clients, see `replay`.
+    pub replayed: Vec<Arc<Track>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Client {
     pub client_id: String,
     pub peer_connection: Option<Box<webrtc::WebRTCConnection>>,
-    pub sender: Arc<Box<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>>,
-    pub group: Option<Arc<Group>>,
+    pub sender: ClientSender,
+    pub group: Option<String>,
+    pub role: Role,
+    pub protocol_version: Option<u32>,
+    pub claims: Option<Claims>,
+    /// Remote candidates received before the offer creating the peer connection.
+    pub pending_candidates: Vec<RTCIceCandidateInit>,
+    /// Video the client receives at most, handed to its peer connection.
+    pub last_n: LastN,
+    /// Lets a reconnecting websocket take the session over.
+    pub resume_token: String,
+    /// Counts the websockets the session was attached to, a stale one must not detach it.
+    pub epoch: u64,
+    pub outbound: Option<ws::Outbound>,
+}
+
+impl Client {
+    /// Who the client is: the subject of its token, its id without authentication.
+    pub fn identity(&self) -> &str {
+        match &self.claims {
+            Some(claims) => &claims.sub,
+            None => &self.client_id,
+        }
+    }
+}
+
+impl Default for Group {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Group {
     pub fn new() -> Group {
         let clients = Arc::new(Mutex::new(Vec::new()));
-        Group { clients }
+        let codecs = Arc::new(Mutex::new(GroupCodecs::default()));
+        let speakers = Arc::new(std::sync::Mutex::new(SpeakerDetector::default()));
+        Group {
+            clients,
+            codecs,
+            speakers,
+            recorder: None,
+            replayed: vec![],
+        }
     }
 
     pub async fn subscribe(&mut self, client: Arc<Mutex<Client>>) {
         self.clients.lock().await.push(client);
     }
 
+    /// Removes `client` from the group and returns how many clients are left.
+    pub async fn unsubscribe(&mut self, client: &Arc<Mutex<Client>>) -> usize {
+        let mut clients = self.clients.lock().await;
+        clients.retain(|member| !Arc::ptr_eq(member, client));
+        clients.len()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.clients.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.clients.lock().await.is_empty()
+    }
+
     pub async fn add_tracks(&self, to_peer: &mut Box<WebRTCConnection>) {
         let clients = self.clients.lock().await;
         for client in clients.iter() {
@@ -44,65 +133,481 @@ impl Group {
             if let Some(pc) = &client.lock().await.peer_connection {
                 debug!("Got peer_connection {:?}", pc);
                 for track in pc.get_tracks().lock().await.values() {
-                    to_peer.add_remote_track(&track).await;
+                    if !to_peer.wants_track(track) {
+                        continue;
+                    }
+                    to_peer.add_remote_track(track).await;
                     debug!("Adding track {:?} to peer {:?}\n", track, to_peer.get_id());
                 }
             }
         }
+        for track in &self.replayed {
+            if to_peer.wants_track(track) {
+                to_peer.add_remote_track(track).await;
+            }
+        }
+        to_peer.apply_last_n().await;
     }
 
     pub async fn notify_track(&mut self, track: &Arc<Track>) {
+        if let Some(recorder) = &self.recorder {
+            recorder.add_track(track);
+        }
         let mut clients = self.clients.lock().await;
         for client in clients.iter_mut() {
             if let Some(pc) = &mut client.lock().await.peer_connection {
-                pc.add_remote_track(&track).await;
+                if pc.wants_track(track) {
+                    pc.add_remote_track(track).await;
+                    pc.apply_last_n().await;
+                }
             }
         }
     }
+
+    /// Sends `message` to every member of the group.
+    pub async fn broadcast(&self, message: &ServerMessage) {
+        let clients = self.clients.lock().await;
+        for client in clients.iter() {
+            send_message(&client.lock().await.sender, message);
+        }
+    }
+
+    /// Sends `message` to every member of the group playing `role`.
+    pub async fn broadcast_to_role(&self, role: Role, message: &ServerMessage) {
+        let clients = self.clients.lock().await;
+        for client in clients.iter() {
+            let client = client.lock().await;
+            if client.role == role {
+                send_message(&client.sender, message);
+            }
+        }
+    }
+
+    pub async fn remove_tracks(&self, tracks: &[Arc<Track>]) {
+        if let Some(recorder) = &self.recorder {
+            for track in tracks {
+                recorder.remove_track(track);
+            }
+        }
+        let clients = self.clients.lock().await;
+        for client in clients.iter() {
+            if let Some(pc) = &client.lock().await.peer_connection {
+                for track in tracks {
+                    pc.remove_remote_track(track).await;
+                }
+                pc.apply_last_n().await;
+            }
+        }
+        drop(clients);
+        self.release_codecs().await;
+    }
+
+    /// Unpins the codecs of the kinds no track of the group is published with anymore.
+    pub async fn release_codecs(&self) {
+        let mut published: Vec<_> = self.replayed.iter().map(|track| track.kind()).collect();
+        for client in self.clients.lock().await.iter() {
+            if let Some(pc) = &client.lock().await.peer_connection {
+                published.extend(
+                    pc.get_tracks()
+                        .lock()
+                        .await
+                        .values()
+                        .map(|track| track.kind()),
+                );
+            }
+        }
+        self.codecs.lock().await.release(&published);
+    }
+
+    /// Starts recording the tracks of group `group_id` into `directory` and tells the members.
+    /// Returns false if the group is being recorded already.
+    pub async fn start_recording(&mut self, group_id: &str, directory: &Path) -> bool {
+        if self.recorder.is_some() {
+            return false;
+        }
+        let recorder = Arc::new(Recorder::new(group_id, directory));
+        {
+            let clients = self.clients.lock().await;
+            for client in clients.iter() {
+                if let Some(pc) = &client.lock().await.peer_connection {
+                    for track in pc.get_tracks().lock().await.values() {
+                        recorder.add_track(track);
+                    }
+                }
+            }
+        }
+        for track in &self.replayed {
+            recorder.add_track(track);
+        }
+        self.recorder = Some(recorder);
+        info!("Recording group {}", group_id);
+        self.broadcast(&ServerMessage::Recording { recording: true })
+            .await;
+        true
+    }
+
+    /// Stops recording and tells the members, returns false if the group wasn't being recorded.
+    pub async fn stop_recording(&mut self) -> bool {
+        let recorder = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return false,
+        };
+        recorder.stop();
+        self.broadcast(&ServerMessage::Recording { recording: false })
+            .await;
+        true
+    }
+
+    /// Publishes `tracks`, replayed from an RTP dump, to the members.
+    pub async fn add_replayed_tracks(&mut self, tracks: &[Arc<Track>]) {
+        for track in tracks {
+            self.replayed.push(track.clone());
+            self.notify_track(track).await;
+        }
+    }
+
+    /// Withdraws `tracks` at the end of their replay.
+    pub async fn remove_replayed_tracks(&mut self, tracks: &[Arc<Track>]) {
+        self.replayed
+            .retain(|replayed| !tracks.iter().any(|track| Arc::ptr_eq(track, replayed)));
+        self.remove_tracks(tracks).await;
+    }
+
+    /// Works out again which video every member receives, after the dominant speaker changed.
+    pub async fn apply_last_n(&self) {
+        let clients = self.clients.lock().await;
+        for client in clients.iter() {
+            if let Some(pc) = &client.lock().await.peer_connection {
+                pc.apply_last_n().await;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// How long an empty group is kept around before it is torn down.
+    pub group_grace_period: Duration,
+    /// Maximum number of clients in a group, unlimited when unset.
+    pub max_group_clients: Option<usize>,
+    /// Video tracks forwarded to a client that didn't pick a limit, all when unset.
+    pub last_n: Option<usize>,
+    /// How long a disconnected client may resume its session, resumption is off when zero.
+    pub resume_grace_period: Duration,
+    pub ice_servers: Vec<RTCIceServer>,
+    /// Codecs and transport policy every peer connection is created with.
+    pub api: Arc<API>,
+    /// Codecs peer connections negotiate, see `webrtc::supported_codecs`.
+    pub codecs: Vec<RTCRtpCodecCapability>,
+    /// Verifies client tokens, authentication is disabled when unset.
+    pub authenticator: Option<Authenticator>,
+    /// Embedded TURN server issuing credentials to clients, disabled when unset.
+    pub turn: Option<Arc<TurnServer>>,
+    /// Bearer token of the admin API, which is disabled when unset.
+    pub admin_token: Option<String>,
+    /// Directory recordings are written to, recording is disabled when unset.
+    pub recording_directory: Option<PathBuf>,
+    /// Directory every peer connection dumps its packets to, see `dump`. Off when unset.
+    pub rtp_dump_directory: Option<PathBuf>,
+    /// Origins browsers may connect from, `*` allowing any.
+    pub allowed_origins: Vec<String>,
+    /// Ids of the clients by resume token, see `ws::resume_session`.
+    pub resume_tokens: Arc<std::sync::Mutex<HashMap<String, String>>>,
+}
+
+impl ServerOptions {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    /// ICE servers handed to a client: the configured ones plus fresh credentials for the
+    /// embedded TURN server.
+    pub fn client_ice_servers(&self) -> Vec<IceServer> {
+        let mut ice_servers: Vec<IceServer> = self
+            .ice_servers
+            .iter()
+            .map(|server| IceServer {
+                urls: server.urls.clone(),
+                username: Some(server.username.clone()).filter(|name| !name.is_empty()),
+                credential: Some(server.credential.clone()).filter(|cred| !cred.is_empty()),
+            })
+            .collect();
+        if let Some(turn) = &self.turn {
+            match turn.issue_credentials() {
+                Ok(server) => ice_servers.push(server),
+                Err(err) => warn!("Unable to issue TURN credentials: {}", err),
+            }
+        }
+        ice_servers
+    }
 }
 
 type Clients = Arc<Mutex<HashMap<String, Arc<Mutex<Client>>>>>;
 type Groups = Arc<Mutex<HashMap<String, Arc<Mutex<Group>>>>>;
+/// Clients publishing over WHIP, keyed by the id of their session.
+type WhipSessions = Arc<Mutex<HashMap<String, Arc<Mutex<Client>>>>>;
 type Result<T> = std::result::Result<T, Rejection>;
 
 #[tokio::main]
 async fn main() {
     let matches = Command::new("signaler")
         .version("0.01")
-        .arg(arg!(--port <VALUE>).default_value("9999").required(false))
+        .arg(
+            arg!(--config <PATH> "TOML configuration file")
+                .env("SIGNAL_SERVER_CONFIG")
+                .value_parser(value_parser!(PathBuf))
+                .required(false),
+        )
+        .arg(
+            arg!(--port <VALUE>)
+                .env("SIGNAL_SERVER_PORT")
+                .value_parser(value_parser!(u16))
+                .required(false),
+        )
         .arg(
             arg!(--addr <VALUE>)
-                .default_value("127.0.0.1")
+                .env("SIGNAL_SERVER_ADDR")
+                .required(false),
+        )
+        .arg(
+            arg!(--"allowed-origins" <ORIGINS> "Comma separated origins browsers may connect from")
+                .env("SIGNAL_SERVER_ALLOWED_ORIGINS")
+                .value_delimiter(',')
+                .required(false),
+        )
+        .arg(
+            arg!(--"group-grace-period" <SECONDS> "Seconds an empty group is kept before teardown")
+                .env("SIGNAL_SERVER_GROUP_GRACE_PERIOD")
+                .value_parser(value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            arg!(--"auth-secret" <SECRET> "Shared secret verifying client tokens")
+                .env("SIGNAL_SERVER_AUTH_SECRET")
+                .required(false),
+        )
+        .arg(
+            arg!(--"admin-token" <TOKEN> "Bearer token of the admin HTTP API")
+                .env("SIGNAL_SERVER_ADMIN_TOKEN")
+                .required(false),
+        )
+        .arg(
+            arg!(--"recording-directory" <PATH> "Directory group recordings are written to")
+                .env("SIGNAL_SERVER_RECORDING_DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
+                .required(false),
+        )
+        .arg(
+            arg!(--"rtp-dump-directory" <PATH> "Directory the packets of every peer connection are dumped to")
+                .env("SIGNAL_SERVER_RTP_DUMP_DIRECTORY")
+                .value_parser(value_parser!(PathBuf))
                 .required(false),
         )
         .get_matches();
 
-    start_logger();
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+
+    start_logger(&config.log.filter);
+
+    let authenticator = config.auth.secret.as_deref().map(Authenticator::new);
+    if authenticator.is_none() {
+        warn!("No auth secret configured, any client may join any group");
+    }
+    let api = match webrtc::build_api(&config).await {
+        Ok(api) => api,
+        Err(err) => {
+            error!("Unable to set up webrtc: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let codecs = match webrtc::supported_codecs(&api).await {
+        Ok(codecs) => codecs,
+        Err(err) => {
+            error!("Unable to list the codecs of webrtc: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let turn = if config.turn.enabled {
+        match TurnServer::start(&config.turn).await {
+            Ok(turn) => Some(Arc::new(turn)),
+            Err(err) => {
+                error!("Unable to start the TURN server: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let options = ServerOptions {
+        group_grace_period: config.group_grace_period(),
+        max_group_clients: config.groups.max_clients,
+        last_n: config.groups.last_n,
+        resume_grace_period: config.resume_grace_period(),
+        ice_servers: config.rtc_ice_servers(),
+        api: Arc::new(api),
+        codecs,
+        authenticator,
+        turn,
+        admin_token: config.admin.token.clone(),
+        recording_directory: config.recording.directory.clone(),
+        rtp_dump_directory: config.debug.rtp_dump_directory.clone(),
+        allowed_origins: config.server.allowed_origins.clone(),
+        resume_tokens: Arc::default(),
+    };
 
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
     let groups: Groups = Arc::new(Mutex::new(HashMap::new()));
+    let whip_sessions: WhipSessions = Arc::new(Mutex::new(HashMap::new()));
 
     let signal = warp::path("signal")
         .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("origin"))
         .and(with_clients(clients.clone()))
         .and(with_groups(groups.clone()))
+        .and(with_options(options.clone()))
         .and_then(handler::ws_handler);
 
-    let routes = signal.with(warp::cors().allow_any_origin());
-    let addr = matches.get_one::<String>("addr").unwrap();
-    let port = matches.get_one::<String>("port").unwrap();
-    let port = port.parse::<u16>().unwrap();
-    let addr: SocketAddr = format!("{}:{}", addr, port)
-        .parse()
-        .expect("Unable to parse ip address");
+    let stats = warp::path!("admin" / "stats")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_groups(groups.clone()))
+        .and(with_options(options.clone()))
+        .and_then(admin::stats_handler);
+
+    let recording = warp::path!("admin" / "groups" / String / "recording")
+        .and(
+            warp::post()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_groups(groups.clone()))
+        .and(with_options(options.clone()))
+        .and_then(admin::recording_handler);
+
+    let replay = warp::path!("admin" / "groups" / String / "replay")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_groups(groups.clone()))
+        .and(with_options(options.clone()))
+        .and_then(admin::replay_handler);
+
+    let whip_publish = warp::path!(String)
+        .and(warp::post())
+        .and(warp::query::<whip::WhipQuery>())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(whip::MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_whip_sessions(whip_sessions.clone()))
+        .and(with_groups(groups.clone()))
+        .and(with_options(options.clone()))
+        .and_then(whip::publish_handler);
+
+    let whip_trickle = warp::path!(String / String)
+        .and(warp::patch())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(whip::MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_whip_sessions(whip_sessions.clone()))
+        .and(with_options(options.clone()))
+        .and_then(whip::trickle_handler);
+
+    let whip_delete = warp::path!(String / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_whip_sessions(whip_sessions))
+        .and(with_groups(groups.clone()))
+        .and(with_options(options))
+        .and_then(whip::delete_handler);
+
+    // Browser based WHIP clients send preflight requests and read the session location. The
+    // prefix is matched outside the CORS filter, which answers preflight requests for any path.
+    let whip = warp::path("whip").and(
+        whip_publish
+            .or(whip_trickle)
+            .or(whip_delete)
+            .with(whip_cors(&config.server.allowed_origins)),
+    );
+    let routes = signal.or(stats).or(recording).or(replay).or(whip);
+    let addr = config.listen_addr();
     info!("Starting server on {}", addr);
     warp::serve(routes).run(addr).await;
 }
 
+/// Reads the configuration file, if any, and applies the command line overrides on top of it.
+fn load_config(matches: &ArgMatches) -> anyhow::Result<Config> {
+    let mut config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(addr) = matches.get_one::<String>("addr") {
+        config.server.addr = addr.clone();
+    }
+    if let Some(port) = matches.get_one::<u16>("port") {
+        config.server.port = *port;
+    }
+    if let Some(origins) = matches.get_many::<String>("allowed-origins") {
+        config.server.allowed_origins = origins.cloned().collect();
+    }
+    if let Some(grace_period) = matches.get_one::<u64>("group-grace-period") {
+        config.groups.grace_period = *grace_period;
+    }
+    if let Some(secret) = matches.get_one::<String>("auth-secret") {
+        config.auth.secret = Some(secret.clone());
+    }
+    if let Some(token) = matches.get_one::<String>("admin-token") {
+        config.admin.token = Some(token.clone());
+    }
+    if let Some(directory) = matches.get_one::<PathBuf>("recording-directory") {
+        config.recording.directory = Some(directory.clone());
+    }
+    if let Some(directory) = matches.get_one::<PathBuf>("rtp-dump-directory") {
+        config.debug.rtp_dump_directory = Some(directory.clone());
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn whip_cors(allowed_origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(vec!["POST", "PATCH", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type", "if-match"])
+        .expose_headers(vec!["location", "link", "etag"]);
+    if allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        // validate() made sure every origin parses
+        cors.allow_origins(allowed_origins.iter().map(String::as_str))
+    }
+}
+
 fn with_groups(groups: Groups) -> impl Filter<Extract = (Groups,), Error = Infallible> + Clone {
     warp::any().map(move || groups.clone())
 }
 
+fn with_options(
+    options: ServerOptions,
+) -> impl Filter<Extract = (ServerOptions,), Error = Infallible> + Clone {
+    warp::any().map(move || options.clone())
+}
+
+fn with_whip_sessions(
+    sessions: WhipSessions,
+) -> impl Filter<Extract = (WhipSessions,), Error = Infallible> + Clone {
+    warp::any().map(move || sessions.clone())
+}
+
 fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = Infallible> + Clone {
     warp::any().map(move || clients.clone())
 }
@@ -0,0 +1,291 @@
+//! Signaling protocol spoken over the `/signal` websocket.
+//!
+//! Every message is a JSON object tagged by its `type` field, e.g.
+//! `{"type": "join", "group": "robot-1"}`. Clients may attach an `id` to any message so
+//! replies referring to it can be correlated. Right after the websocket connects the server sends
+//! `config`, the first message of the client has to be `hello`, which the server answers with
+//! `welcome` carrying the agreed protocol version.
+use crate::role::Role;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use tokio::sync::mpsc;
+use tracing::warn;
+use warp::ws::Message;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+/// Newest protocol version understood by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this server is still able to speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub type ClientSender = mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>;
+pub type ClientReceiver = mpsc::UnboundedReceiver<std::result::Result<Message, warp::Error>>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientEnvelope {
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+/// Messages sent from a client to the server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    Hello {
+        protocol_version: u32,
+        token: Option<String>,
+    },
+    Join {
+        group: String,
+        role: Option<Role>,
+    },
+    Leave,
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    Ice {
+        ice: RTCIceCandidateInit,
+    },
+    /// Command for the robots of the group, only operators may send them.
+    Control {
+        command: Value,
+    },
+    /// Picks the simulcast layer of a track forwarded to the client, the server picks when
+    /// `rid` is absent.
+    SelectLayer {
+        track_id: String,
+        rid: Option<String>,
+    },
+    /// Stops forwarding a track to the client, until it is resumed.
+    Pause {
+        track_id: String,
+    },
+    Resume {
+        track_id: String,
+    },
+    /// Stops forwarding the tracks of `kind` the client publishes to everyone, until they are
+    /// unmuted.
+    Mute {
+        kind: String,
+    },
+    Unmute {
+        kind: String,
+    },
+    /// Limits the video forwarded to the client to `limit` tracks, all when absent: those of
+    /// the `pinned` stream ids first, then those of the latest dominant speakers.
+    LastN {
+        limit: Option<usize>,
+        #[serde(default)]
+        pinned: Vec<String>,
+    },
+    /// Starts or stops recording the group, only operators may.
+    StartRecording,
+    StopRecording,
+}
+
+/// ICE server as handed to `RTCPeerConnection` by browsers.
+#[derive(Debug, Clone, Serialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Audio level of a publisher, see `ServerMessage::AudioLevels`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioLevel {
+    pub stream_id: String,
+    /// In -dBov as in RFC 6464, from 0 for the loudest to 127 for silence.
+    pub level: u8,
+}
+
+/// Limits of the groups a client may join.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupLimits {
+    /// Maximum number of clients in a group, unlimited when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clients: Option<usize>,
+}
+
+/// Messages sent from the server to a client.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    /// Sent as soon as the websocket is connected.
+    Config {
+        /// Version negotiated from the `protocol_version` query parameter, the newest otherwise.
+        protocol_version: u32,
+        client_id: String,
+        /// ICE servers the client should use, TURN credentials are only valid for a while.
+        ice_servers: Vec<IceServer>,
+        limits: GroupLimits,
+        /// Presented as the `resume` query parameter to take the session over after a reconnect.
+        resume_token: String,
+        /// Whether this websocket resumed an existing session.
+        resumed: bool,
+    },
+    Welcome {
+        protocol_version: u32,
+        client_id: String,
+    },
+    Joined {
+        group: String,
+        role: Role,
+        /// Whether the group is being recorded, see `ServerMessage::Recording`.
+        recording: bool,
+    },
+    Left {
+        group: String,
+    },
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    Ice {
+        ice: RTCIceCandidateInit,
+    },
+    TrackAdded {
+        track_id: String,
+        stream_id: String,
+        kind: String,
+        /// Rids of the simulcast layers that can be selected, empty without simulcast.
+        layers: Vec<String>,
+        muted: bool,
+    },
+    TrackRemoved {
+        track_id: String,
+        stream_id: String,
+    },
+    Control {
+        from: String,
+        command: Value,
+    },
+    /// The track is now forwarded in simulcast layer `rid`.
+    LayerSwitched {
+        track_id: String,
+        rid: String,
+    },
+    /// Confirms `pause` and `resume` of a track forwarded to the client.
+    TrackPaused {
+        track_id: String,
+        paused: bool,
+    },
+    /// The publisher of the track muted or unmuted it, sent to the whole group.
+    TrackMuted {
+        track_id: String,
+        stream_id: String,
+        muted: bool,
+    },
+    /// The publisher of `stream_id` became the dominant speaker of the group.
+    ActiveSpeaker {
+        stream_id: String,
+    },
+    /// Smoothed levels of the publishers speaking, a few times a second while anybody speaks
+    /// and once with no levels when everybody went silent.
+    AudioLevels {
+        levels: Vec<AudioLevel>,
+    },
+    /// Video tracks forwarded to a client with a last-N limit whenever they change, its other
+    /// video tracks are paused.
+    VideoForwarded {
+        track_ids: Vec<String>,
+    },
+    /// The group started or stopped being recorded, sent to the whole group.
+    Recording {
+        recording: bool,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        in_reply_to: Option<String>,
+    },
+}
+
+/// Machine-readable reason attached to an `error` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    HandshakeRequired,
+    UnsupportedVersion,
+    NotInGroup,
+    NoPeerConnection,
+    PeerConnectionFailed,
+    NegotiationFailed,
+    OfferCollision,
+    InvalidCandidate,
+    Unauthorized,
+    Forbidden,
+    GroupFull,
+    UnsupportedCodec,
+    UnknownTrack,
+    UnknownLayer,
+    RecordingDisabled,
+}
+
+/// Failure while handling a client message, reported back to that client only.
+#[derive(Debug, Clone)]
+pub struct SignalError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl SignalError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> SignalError {
+        SignalError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn to_message(&self, in_reply_to: Option<String>) -> ServerMessage {
+        ServerMessage::Error {
+            code: self.code,
+            message: self.message.clone(),
+            in_reply_to,
+        }
+    }
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+/// Picks the protocol version to use with a client announcing `client_version`.
+pub fn negotiate_version(client_version: u32) -> Option<u32> {
+    if client_version < MIN_PROTOCOL_VERSION {
+        return None;
+    }
+    Some(client_version.min(PROTOCOL_VERSION))
+}
+
+pub fn encode_message(message: &ServerMessage) -> Option<Message> {
+    match serde_json::to_string(message) {
+        Ok(text) => Some(Message::text(text)),
+        Err(err) => {
+            warn!("Unable to serialize message {:?}: {}", message, err);
+            None
+        }
+    }
+}
+
+pub fn send_message(sender: &ClientSender, message: &ServerMessage) {
+    let message = match encode_message(message) {
+        Some(message) => message,
+        None => return,
+    };
+    if let Err(err) = sender.send(Ok(message)) {
+        warn!("Error sending message {:?}", err);
+    }
+}
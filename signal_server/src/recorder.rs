@@ -0,0 +1,590 @@
+//! Records the tracks of a group to disk, one file per track: IVF for VP8 and VP9 video, Ogg for
+//! Opus audio. Tracks in other codecs are left out.
+//!
+//! The recorder subscribes to the tracks like a member of the group and gets the same stream:
+//! the best layer of simulcast tracks, starting on a keyframe, with continuous sequence numbers.
+//! A thread per track puts the packets back in order, waits a little for the retransmission of
+//! lost ones and reassembles the frames. Video frames lost for good are skipped up to the next
+//! keyframe, which is requested right away. Frames are stamped from their RTP timestamps, so that
+//! gaps, e.g. while the track is muted, stay gaps in the recording.
+//!
+//! Files are named after the group, the publishing participant, i.e. the subject of its token,
+//! and when the recording of its peer connection started. The tracks of a peer connection are
+//! timed from then, to keep them in sync.
+use crate::container::{Container, IvfWriter, OggOpusWriter};
+use crate::forward::{DownTrack, Track};
+use crate::keyframe::is_keyframe;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use webrtc::api::media_engine::{MIME_TYPE_OPUS, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::rtp::codecs::{opus::OpusPacket, vp8::Vp8Packet, vp9::Vp9Packet};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+/// How long a missing packet is waited for before the frame it belongs to is given up.
+const REORDER_DELAY: Duration = Duration::from_millis(500);
+/// Packets buffered at most while waiting for a missing one.
+const MAX_BUFFERED_PACKETS: usize = 2048;
+
+#[derive(Debug)]
+pub struct Recorder {
+    /// Subscribes to the tracks under this id.
+    id: String,
+    group_id: String,
+    directory: PathBuf,
+    /// Tracks being recorded by id, with the file they are written to.
+    recordings: Mutex<HashMap<String, (Arc<Track>, PathBuf)>>,
+    /// When the recording of each publisher started, by stream id.
+    publishers: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Recorder {
+    pub fn new(group_id: &str, directory: &Path) -> Recorder {
+        Recorder {
+            id: format!("recorder-{}", Uuid::new_v4()),
+            group_id: group_id.to_owned(),
+            directory: directory.to_owned(),
+            recordings: Mutex::new(HashMap::new()),
+            publishers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Files written so far.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let recordings = self.recordings.lock().unwrap();
+        let mut files: Vec<_> = recordings.values().map(|(_, path)| path.clone()).collect();
+        files.sort();
+        files
+    }
+
+    /// Starts recording `track`, unless it is recorded already or in a codec that can't be.
+    pub fn add_track(&self, track: &Arc<Track>) {
+        if self.recordings.lock().unwrap().contains_key(track.id()) {
+            return;
+        }
+        let codec = track.codec();
+        let mime_type = codec.mime_type.as_str();
+        let (depacketizer, extension): (Box<dyn Depacketizer + Send>, _) =
+            if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+                (Box::new(Vp8Packet::default()), "ivf")
+            } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+                (Box::new(Vp9Packet::default()), "ivf")
+            } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+                (Box::new(OpusPacket), "ogg")
+            } else {
+                warn!(
+                    "Not recording track {}, {} can't be recorded",
+                    track.id(),
+                    mime_type
+                );
+                return;
+            };
+
+        let (started, start) = self
+            .publishers
+            .lock()
+            .unwrap()
+            .entry(track.publisher_id().to_owned())
+            .or_insert_with(|| {
+                let started = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+                (started, Instant::now())
+            })
+            .clone();
+        let name = format!(
+            "{}_{}_{}_{}",
+            file_name_part(&self.group_id),
+            file_name_part(track.participant()),
+            started,
+            track.kind(),
+        );
+        let path = unused_path(&self.directory, &name, extension);
+        let container = std::fs::create_dir_all(&self.directory).and_then(|_| {
+            let container: Box<dyn Container> = if extension == "ogg" {
+                Box::new(OggOpusWriter::create(&path, codec.channels.max(1) as u8)?)
+            } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+                Box::new(IvfWriter::create(&path, b"VP80", codec.clock_rate)?)
+            } else {
+                Box::new(IvfWriter::create(&path, b"VP90", codec.clock_rate)?)
+            };
+            Ok(container)
+        });
+        let container = match container {
+            Ok(container) => container,
+            Err(err) => {
+                warn!("Unable to record to {}: {}", path.display(), err);
+                return;
+            }
+        };
+
+        info!("Recording track {} to {}", track.id(), path.display());
+        let (down_track, packets) = track.record(&self.id);
+        let recording = TrackRecording {
+            frames: FrameBuffer::new(depacketizer, mime_type),
+            container,
+            down_track: Arc::downgrade(&down_track),
+            video: track.kind() == RTPCodecType::Video,
+            clock_rate: codec.clock_rate,
+            start,
+        };
+        let description = path.display().to_string();
+        std::thread::spawn(move || recording.run(packets, &description));
+        self.recordings
+            .lock()
+            .unwrap()
+            .insert(track.id().to_owned(), (track.clone(), path));
+    }
+
+    /// Stops recording `track`, its file is completed once the packets received are written.
+    pub fn remove_track(&self, track: &Track) {
+        if self.recordings.lock().unwrap().remove(track.id()).is_some() {
+            track.unsubscribe(&self.id);
+        }
+    }
+
+    /// Stops recording every track.
+    pub fn stop(&self) {
+        for (_, (track, _)) in self.recordings.lock().unwrap().drain() {
+            track.unsubscribe(&self.id);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// `<name>.<extension>` in `directory`, or `<name>-<n>.<extension>` when taken, e.g. by another
+/// track of the same kind or an earlier peer connection of the participant.
+fn unused_path(directory: &Path, name: &str, extension: &str) -> PathBuf {
+    let mut path = directory.join(format!("{}.{}", name, extension));
+    let mut n = 2;
+    while path.exists() {
+        path = directory.join(format!("{}-{}.{}", name, n, extension));
+        n += 1;
+    }
+    path
+}
+
+/// Keeps the characters of `part` which are safe in file names.
+fn file_name_part(part: &str) -> String {
+    part.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Writes one track, on a thread of its own.
+struct TrackRecording {
+    frames: FrameBuffer,
+    container: Box<dyn Container>,
+    /// Asked for a keyframe after a frame was lost.
+    down_track: Weak<DownTrack>,
+    video: bool,
+    clock_rate: u32,
+    /// When the recording of the publisher started.
+    start: Instant,
+}
+
+impl TrackRecording {
+    /// Writes the frames of the packets received until the track is unsubscribed.
+    fn run(mut self, mut packets: mpsc::UnboundedReceiver<Packet>, description: &str) {
+        // RTP timestamp of the first frame and its presentation time.
+        let mut origin = None;
+        let mut waiting_keyframe = self.video;
+        let mut keyframe_requested = false;
+        let mut closed = false;
+        while !closed {
+            match packets.blocking_recv() {
+                Some(packet) => self.frames.push(packet),
+                None => closed = true,
+            }
+            while let Some(frame) = self.frames.pop(closed) {
+                if self.video {
+                    waiting_keyframe |= frame.after_loss;
+                    if waiting_keyframe && !frame.keyframe {
+                        if !keyframe_requested {
+                            debug!("Recording of {} waits for a keyframe", description);
+                            if let Some(down_track) = self.down_track.upgrade() {
+                                down_track.resync();
+                            }
+                            keyframe_requested = true;
+                        }
+                        continue;
+                    }
+                    waiting_keyframe = false;
+                    keyframe_requested = false;
+                }
+                let (first_timestamp, first_pts) = *origin.get_or_insert_with(|| {
+                    let offset = self.start.elapsed().as_secs_f64() * f64::from(self.clock_rate);
+                    (frame.timestamp, offset as u64)
+                });
+                let pts = first_pts + frame.timestamp.saturating_sub(first_timestamp);
+                if let Err(err) = self.container.write_frame(pts, &frame.data) {
+                    warn!("Error recording to {}: {}", description, err);
+                    return;
+                }
+            }
+        }
+        match self.container.finish() {
+            Ok(()) => info!("Recorded {}", description),
+            Err(err) => warn!("Error completing {}: {}", description, err),
+        }
+    }
+}
+
+/// Frame put together from the packets of a track.
+struct Frame {
+    data: Vec<u8>,
+    /// RTP timestamp, extended beyond 32 bits.
+    timestamp: u64,
+    keyframe: bool,
+    /// Set when frames before this one were lost.
+    after_loss: bool,
+}
+
+/// Puts packets back in order and reassembles the frames they carry.
+struct FrameBuffer {
+    depacketizer: Box<dyn Depacketizer + Send>,
+    mime_type: String,
+    /// Packets waiting for their frame to be complete, by extended sequence number, with when
+    /// they arrived.
+    packets: BTreeMap<u64, (Packet, Instant)>,
+    /// Extended sequence number of the packet starting the next frame, unset before the first
+    /// packet.
+    next: Option<u64>,
+    /// Newest extended sequence number and timestamp received, the references to extend others.
+    newest_sequence: u64,
+    newest_timestamp: u64,
+    /// Set when a frame was given up since the last one returned.
+    lost: bool,
+}
+
+impl FrameBuffer {
+    fn new(depacketizer: Box<dyn Depacketizer + Send>, mime_type: &str) -> FrameBuffer {
+        FrameBuffer {
+            depacketizer,
+            mime_type: mime_type.to_owned(),
+            packets: BTreeMap::new(),
+            next: None,
+            newest_sequence: 0,
+            newest_timestamp: 0,
+            lost: false,
+        }
+    }
+
+    fn push(&mut self, packet: Packet) {
+        let sequence_number = match self.next {
+            Some(_) => extend(
+                self.newest_sequence,
+                u64::from(packet.header.sequence_number),
+                16,
+            ),
+            None => {
+                // Far enough from zero for packets reordered before the first one.
+                self.newest_timestamp = (1 << 32) + u64::from(packet.header.timestamp);
+                let first = (1 << 16) + u64::from(packet.header.sequence_number);
+                self.newest_sequence = first;
+                self.next = Some(first);
+                first
+            }
+        };
+        // Too late, the frame was written or given up already.
+        if Some(sequence_number) < self.next {
+            return;
+        }
+        self.newest_sequence = self.newest_sequence.max(sequence_number);
+        self.packets
+            .insert(sequence_number, (packet, Instant::now()));
+    }
+
+    /// The next frame once it is complete, or once the packets missing from it are given up.
+    /// At the `end` of the track nothing more is waited for.
+    fn pop(&mut self, end: bool) -> Option<Frame> {
+        loop {
+            let next = self.next?;
+            let oldest = self.packets.values().next().map(|(_, arrived)| *arrived)?;
+            let give_up = end
+                || oldest.elapsed() >= REORDER_DELAY
+                || self.packets.len() > MAX_BUFFERED_PACKETS;
+            let head = match self.packets.get(&next) {
+                Some((head, _)) => head,
+                None if give_up => {
+                    self.lost = true;
+                    self.next = self.packets.keys().next().copied();
+                    continue;
+                }
+                None => return None,
+            };
+            // Padding sent to probe the bandwidth, it carries no media.
+            if head.payload.is_empty() {
+                self.packets.remove(&next);
+                self.next = Some(next + 1);
+                continue;
+            }
+            if !self.depacketizer.is_partition_head(&head.payload) {
+                // The start of the frame was lost.
+                self.lost = true;
+                self.packets.remove(&next);
+                self.next = Some(next + 1);
+                continue;
+            }
+            let timestamp = head.header.timestamp;
+            let mut tail = None;
+            for sequence_number in next.. {
+                match self.packets.get(&sequence_number) {
+                    Some((packet, _)) if packet.header.timestamp == timestamp => {
+                        if self
+                            .depacketizer
+                            .is_partition_tail(packet.header.marker, &packet.payload)
+                        {
+                            tail = Some(sequence_number);
+                            break;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            let tail = match tail {
+                Some(tail) => tail,
+                None if give_up => {
+                    self.lost = true;
+                    self.packets.remove(&next);
+                    self.next = Some(next + 1);
+                    continue;
+                }
+                None => return None,
+            };
+
+            self.next = Some(tail + 1);
+            let packets: Vec<_> = (next..=tail)
+                .filter_map(|sequence_number| self.packets.remove(&sequence_number))
+                .map(|(packet, _)| packet)
+                .collect();
+            let keyframe = is_keyframe(&self.mime_type, &packets[0].payload);
+            let mut data = vec![];
+            for packet in &packets {
+                match self.depacketizer.depacketize(&packet.payload) {
+                    Ok(payload) => data.extend_from_slice(&payload),
+                    Err(err) => {
+                        debug!("Dropping frame which can't be depacketized: {}", err);
+                        self.lost = true;
+                        data.clear();
+                        break;
+                    }
+                }
+            }
+            if data.is_empty() {
+                continue;
+            }
+            let timestamp = extend(self.newest_timestamp, u64::from(timestamp), 32);
+            self.newest_timestamp = self.newest_timestamp.max(timestamp);
+            return Some(Frame {
+                data,
+                timestamp,
+                keyframe,
+                after_loss: std::mem::take(&mut self.lost),
+            });
+        }
+    }
+}
+
+/// Extends `value`, a counter of `bits` which wraps around, to the 64 bit value closest to
+/// `reference`.
+fn extend(reference: u64, value: u64, bits: u32) -> u64 {
+    let range = 1u64 << bits;
+    let mask = range - 1;
+    let candidate = (reference & !mask) | value;
+    if candidate + range / 2 < reference {
+        candidate + range
+    } else if candidate > reference + range / 2 && candidate >= range {
+        candidate - range
+    } else {
+        candidate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::header::Header;
+
+    const SEQUENCE: u64 = 1 << 16;
+    const TIMESTAMP: u64 = 1 << 32;
+
+    #[test]
+    fn names_files_after_the_participant() {
+        assert_eq!(
+            file_name_part("robot-1/cam@site.org"),
+            "robot-1_cam_site_org"
+        );
+        let directory =
+            std::env::temp_dir().join(format!("signal_server_{}_names", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let first = unused_path(&directory, "g_robot-1_video", "ivf");
+        assert_eq!(first, directory.join("g_robot-1_video.ivf"));
+        std::fs::write(&first, b"").unwrap();
+        let second = unused_path(&directory, "g_robot-1_video", "ivf");
+        assert_eq!(second, directory.join("g_robot-1_video-2.ivf"));
+        std::fs::write(&second, b"").unwrap();
+        assert_eq!(
+            unused_path(&directory, "g_robot-1_video", "ivf"),
+            directory.join("g_robot-1_video-3.ivf")
+        );
+        assert_eq!(
+            unused_path(&directory, "g_robot-1_audio", "ogg"),
+            directory.join("g_robot-1_audio.ogg")
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn extends_wrapping_counters() {
+        let cases = [
+            (SEQUENCE + 100, 90, 16, SEQUENCE + 90),
+            (SEQUENCE + 100, 200, 16, SEQUENCE + 200),
+            // Forwards and backwards across the wrap around.
+            (SEQUENCE + 65535, 0, 16, 2 * SEQUENCE),
+            (2 * SEQUENCE + 1, 65534, 16, SEQUENCE + 65534),
+            (
+                TIMESTAMP + u64::from(u32::MAX) - 10,
+                5,
+                32,
+                2 * TIMESTAMP + 5,
+            ),
+            (
+                2 * TIMESTAMP + 5,
+                u64::from(u32::MAX),
+                32,
+                2 * TIMESTAMP - 1,
+            ),
+            // Nothing goes below zero.
+            (10, 65530, 16, 65530),
+        ];
+        for (reference, value, bits, extended) in cases {
+            assert_eq!(
+                extend(reference, value, bits),
+                extended,
+                "{} {}",
+                reference,
+                value
+            );
+        }
+    }
+
+    /// A VP8 frame of two packets, the first one a keyframe when `keyframe`.
+    fn frame(sequence_number: u16, timestamp: u32, keyframe: bool, data: u8) -> [Packet; 2] {
+        let packet = |sequence_number, marker, payload: [u8; 4]| Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                marker,
+                ..Default::default()
+            },
+            payload: payload.to_vec().into(),
+        };
+        [
+            packet(sequence_number, false, [0x10, u8::from(!keyframe), data, 0]),
+            packet(sequence_number.wrapping_add(1), true, [0x00, data, 1, 2]),
+        ]
+    }
+
+    fn frame_buffer() -> FrameBuffer {
+        FrameBuffer::new(Box::new(Vp8Packet::default()), MIME_TYPE_VP8)
+    }
+
+    fn pop_all(frames: &mut FrameBuffer, end: bool) -> Vec<(u64, bool, bool, Vec<u8>)> {
+        std::iter::from_fn(|| frames.pop(end))
+            .map(|frame| {
+                (
+                    frame.timestamp,
+                    frame.keyframe,
+                    frame.after_loss,
+                    frame.data,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reorders_packets_into_frames() {
+        let mut frames = frame_buffer();
+        let [a1, a2] = frame(65534, u32::MAX - 2999, true, 0xa);
+        let [b1, b2] = frame(0, 0, false, 0xb);
+        let [c1, c2] = frame(2, 3000, false, 0xc);
+        for packet in [a1, b1, a2, c2, b2] {
+            frames.push(packet);
+        }
+        let popped = pop_all(&mut frames, false);
+        assert_eq!(
+            popped,
+            [
+                (
+                    TIMESTAMP + u64::from(u32::MAX) - 2999,
+                    true,
+                    false,
+                    vec![0x00, 0xa, 0, 0xa, 1, 2]
+                ),
+                (2 * TIMESTAMP, false, false, vec![0x01, 0xb, 0, 0xb, 1, 2]),
+            ]
+        );
+        // The last frame waits for its first packet.
+        frames.push(c1);
+        let popped = pop_all(&mut frames, false);
+        assert_eq!(popped.len(), 1);
+        assert_eq!(popped[0].0, 2 * TIMESTAMP + 3000);
+    }
+
+    #[test]
+    fn gives_frames_up_and_drops_late_packets() {
+        let mut frames = frame_buffer();
+        let [a1, a2] = frame(100, 0, true, 0xa);
+        let [_, b2] = frame(102, 3000, false, 0xb);
+        let [c1, c2] = frame(104, 6000, false, 0xc);
+        for packet in [a1, a2, b2, c1, c2] {
+            frames.push(packet);
+        }
+        assert_eq!(pop_all(&mut frames, false).len(), 1);
+        // Not before the reorder delay or the end of the track.
+        assert!(frames.pop(false).is_none());
+        let popped = pop_all(&mut frames, true);
+        assert_eq!(popped.len(), 1);
+        let (timestamp, keyframe, after_loss, _) = &popped[0];
+        assert_eq!(
+            (*timestamp, *keyframe, *after_loss),
+            (TIMESTAMP + 6000, false, true)
+        );
+
+        // Packets of frames gone already are dropped.
+        let [_, late] = frame(100, 0, true, 0xa);
+        frames.push(late);
+        assert!(frames.packets.is_empty());
+        assert!(frames.pop(true).is_none());
+    }
+
+    #[test]
+    fn skips_padding() {
+        let mut frames = frame_buffer();
+        let [a1, a2] = frame(10, 0, true, 0xa);
+        let mut padding = a2.clone();
+        padding.header.sequence_number = 12;
+        padding.payload = Vec::new().into();
+        let [b1, b2] = frame(13, 3000, false, 0xb);
+        for packet in [a1, a2, padding, b1, b2] {
+            frames.push(packet);
+        }
+        let popped = pop_all(&mut frames, false);
+        assert_eq!(popped.len(), 2);
+        assert!(!popped[1].2);
+    }
+}
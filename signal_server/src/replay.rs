@@ -0,0 +1,147 @@
+//! Publishes an RTP dump into a group again, as a publisher without a peer connection, so that
+//! forwarding bugs can be reproduced without the client that ran into them.
+//!
+//! Packets are forwarded with the timing they arrived with. The replayed publisher can't be asked
+//! for keyframes, subscribers joining in the middle wait for the next keyframe of the dump.
+use crate::dump::DumpReader;
+use crate::forward::Track;
+use crate::keyframe::KeyframeRequester;
+use crate::speaker::audio_level;
+use crate::webrtc::pin_codec;
+use crate::Group;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::util::Unmarshal;
+
+/// Where the RTP of one SSRC of the dump goes.
+struct ReplayedLayer {
+    track: Arc<Track>,
+    rid: String,
+    audio_level_extension: Option<u8>,
+}
+
+/// Starts replaying the dump at `path` into `group` and returns the stream id the tracks are
+/// published with. The tracks are withdrawn at the end of the dump.
+pub async fn replay(group: &Arc<Mutex<Group>>, path: &Path) -> Result<String> {
+    let (mut reader, dumped_layers) = DumpReader::open(path)
+        .await
+        .with_context(|| format!("Unable to read RTP dump {}", path.display()))?;
+    if dumped_layers.is_empty() {
+        bail!("RTP dump {} has no layers", path.display());
+    }
+    let stream_id = format!("replay-{}", Uuid::new_v4());
+    // Nobody can be asked for keyframes.
+    let keyframes = Arc::new(KeyframeRequester::new(Weak::new()));
+
+    // Layers are grouped into tracks as they were published, a track's layers share the codec and
+    // role of the first one dumped.
+    let mut tracks: Vec<Arc<Track>> = vec![];
+    let mut dumped_tracks = HashMap::new();
+    let mut layers = HashMap::new();
+    for dumped in &dumped_layers {
+        let kind = RTPCodecType::from(dumped.kind.as_str());
+        if kind == RTPCodecType::Unspecified {
+            bail!("Layer {} has unknown kind {}", dumped.ssrc, dumped.kind);
+        }
+        let track = match dumped_tracks.get(&dumped.track_id) {
+            Some(track) => Arc::clone(track),
+            None => {
+                let rids = dumped_layers
+                    .iter()
+                    .filter(|layer| layer.track_id == dumped.track_id)
+                    .map(|layer| layer.rid.clone())
+                    .collect();
+                let track = Arc::new(Track::new(
+                    format!("{}_{}", stream_id, tracks.len()),
+                    stream_id.clone(),
+                    dumped.role,
+                    kind,
+                    dumped.codec(),
+                    rids,
+                    keyframes.clone(),
+                ));
+                tracks.push(track.clone());
+                dumped_tracks.insert(dumped.track_id.clone(), track.clone());
+                track
+            }
+        };
+        if !track.add_layer(&dumped.rid, dumped.ssrc) {
+            bail!(
+                "Layer {:?} of track {} is dumped twice",
+                dumped.rid,
+                dumped.track_id
+            );
+        }
+        layers.insert(
+            dumped.ssrc,
+            ReplayedLayer {
+                track,
+                rid: dumped.rid.clone(),
+                audio_level_extension: dumped.audio_level_extension,
+            },
+        );
+    }
+    for track in &tracks {
+        pin_codec(group, track.kind(), track.codec()).await;
+    }
+    group.lock().await.add_replayed_tracks(&tracks).await;
+    info!("Replaying {} as {}", path.display(), stream_id);
+
+    let speakers = group.lock().await.speakers.clone();
+    let group = Arc::downgrade(group);
+    let path = path.to_owned();
+    let replayed_stream_id = stream_id.clone();
+    tokio::spawn(async move {
+        let start = Instant::now();
+        loop {
+            let dumped = match reader.next().await {
+                Ok(Some(dumped)) => dumped,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Error reading RTP dump {}: {}", path.display(), err);
+                    break;
+                }
+            };
+            // The RTCP was sent by the dumped peer as a subscriber, there is nobody to send it to.
+            if dumped.rtcp {
+                continue;
+            }
+            let packet = match Packet::unmarshal(&mut dumped.data.as_slice()) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    debug!("Skipping invalid RTP in {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            let layer = match layers.get(&packet.header.ssrc) {
+                Some(layer) => layer,
+                None => continue,
+            };
+            tokio::time::sleep_until(start + dumped.arrival).await;
+            if group.strong_count() == 0 {
+                return;
+            }
+            if let Some(level) = layer
+                .audio_level_extension
+                .and_then(|id| audio_level(&packet, id))
+            {
+                speakers.lock().unwrap().record(&replayed_stream_id, level);
+            }
+            layer.track.forward(&layer.rid, &packet).await;
+        }
+        debug!("Replay of {} ended", path.display());
+        speakers.lock().unwrap().remove(&replayed_stream_id);
+        if let Some(group) = group.upgrade() {
+            group.lock().await.remove_replayed_tracks(&tracks).await;
+        }
+    });
+    Ok(stream_id)
+}
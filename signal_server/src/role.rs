@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+/// Part a client plays in a group, deciding what it may publish and receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Publishes camera and microphone, only receives the operators' audio.
+    Robot,
+    /// Publishes, receives everything and may send control commands.
+    Operator,
+    /// Receives everything, publishes nothing. The least privileged role, played unless another
+    /// one is asked for and allowed.
+    #[default]
+    Viewer,
+}
+
+impl Role {
+    pub fn can_publish(self) -> bool {
+        !matches!(self, Role::Viewer)
+    }
+
+    pub fn can_receive(self, publisher: Role, kind: RTPCodecType) -> bool {
+        match self {
+            Role::Robot => publisher == Role::Operator && kind == RTPCodecType::Audio,
+            Role::Operator | Role::Viewer => true,
+        }
+    }
+
+    pub fn can_control(self) -> bool {
+        self == Role::Operator
+    }
+}
@@ -0,0 +1,297 @@
+//! Dominant speaker detection from the RFC 6464 audio levels publishers attach to their audio.
+//!
+//! Levels are averaged per publisher over each interval and smoothed across intervals. The
+//! loudest publisher above the speech threshold becomes the dominant speaker once it stayed the
+//! loudest for a few intervals in a row, and clearly louder than the dominant speaker if that one
+//! is still speaking, so that coughs and crosstalk don't move the highlight around. The dominant
+//! speaker stays so while everybody is silent.
+use crate::protocol::{AudioLevel, ServerMessage};
+use crate::Group;
+use std::collections::HashMap;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use webrtc::rtp::packet::Packet;
+
+/// How often levels are evaluated and sent to the group.
+const SPEAKER_INTERVAL: Duration = Duration::from_millis(300);
+/// Weight of the latest interval in the smoothed level.
+const SMOOTHING: f64 = 0.4;
+/// Levels in -dBov, louder publishers have lower ones. Above this they are taken as silence.
+const SPEECH_LEVEL: u8 = 60;
+const SILENCE_LEVEL: u8 = 127;
+/// Intervals a publisher has to be the loudest before it becomes the dominant speaker.
+const DOMINANCE_INTERVALS: u32 = 2;
+/// How much louder than a speaking dominant speaker it has to be, in dB.
+const DOMINANCE_MARGIN: u8 = 6;
+
+/// The audio level in -dBov carried by `packet` in extension `extension_id`.
+pub fn audio_level(packet: &Packet, extension_id: u8) -> Option<u8> {
+    let extension = packet.header.get_extension(extension_id)?;
+    extension.first().map(|byte| byte & 0x7f)
+}
+
+#[derive(Debug, Default)]
+pub struct SpeakerDetector {
+    /// Keyed by the stream id of the publisher.
+    speakers: HashMap<String, Speaker>,
+    dominant: Option<String>,
+    /// Publishers that were the dominant speaker, the latest first.
+    recent: Vec<String>,
+    /// The loudest publisher and for how many intervals in a row it was.
+    candidate: Option<(String, u32)>,
+    /// Whether the levels sent last had anybody speaking.
+    was_speaking: bool,
+}
+
+#[derive(Debug, Default)]
+struct Speaker {
+    /// Linear amplitudes received during the current interval.
+    sum: f64,
+    samples: u32,
+    /// Smoothed linear amplitude.
+    amplitude: f64,
+}
+
+impl SpeakerDetector {
+    /// Takes the level of an audio packet of `stream_id`.
+    pub fn record(&mut self, stream_id: &str, level: u8) {
+        let speaker = self.speakers.entry(stream_id.to_owned()).or_default();
+        speaker.sum += amplitude(level);
+        speaker.samples += 1;
+    }
+
+    /// Forgets a publisher which stopped sending audio.
+    pub fn remove(&mut self, stream_id: &str) {
+        self.speakers.remove(stream_id);
+        self.recent.retain(|recent| recent != stream_id);
+        if self.dominant.as_deref() == Some(stream_id) {
+            self.dominant = None;
+        }
+    }
+
+    /// Stream ids of the publishers that were the dominant speaker, the latest first.
+    pub fn recent(&self) -> &[String] {
+        &self.recent
+    }
+
+    /// Ends the current interval and returns the messages for the group: the levels of the
+    /// publishers speaking, and the new dominant speaker when it changed.
+    fn evaluate(&mut self) -> Vec<ServerMessage> {
+        for speaker in self.speakers.values_mut() {
+            let observed = match speaker.samples {
+                0 => 0.0,
+                samples => speaker.sum / f64::from(samples),
+            };
+            speaker.amplitude = speaker.amplitude * (1.0 - SMOOTHING) + observed * SMOOTHING;
+            speaker.sum = 0.0;
+            speaker.samples = 0;
+        }
+
+        let mut levels: Vec<AudioLevel> = self
+            .speakers
+            .iter()
+            .map(|(stream_id, speaker)| AudioLevel {
+                stream_id: stream_id.clone(),
+                level: level(speaker.amplitude),
+            })
+            .filter(|level| level.level <= SPEECH_LEVEL)
+            .collect();
+        levels.sort_by_key(|level| level.level);
+
+        let mut messages = vec![];
+        let dominant_level = levels
+            .iter()
+            .find(|level| self.dominant.as_ref() == Some(&level.stream_id))
+            .map(|level| level.level);
+        let loudest = levels
+            .first()
+            .filter(|loudest| match dominant_level {
+                Some(dominant_level) => loudest.level + DOMINANCE_MARGIN <= dominant_level,
+                None => true,
+            })
+            .map(|level| level.stream_id.clone());
+        self.candidate = match (loudest, self.candidate.take()) {
+            (Some(loudest), Some((candidate, intervals))) if loudest == candidate => {
+                Some((candidate, intervals + 1))
+            }
+            (Some(loudest), _) => Some((loudest, 1)),
+            (None, _) => None,
+        };
+        if let Some((candidate, intervals)) = &self.candidate {
+            if *intervals >= DOMINANCE_INTERVALS && self.dominant.as_ref() != Some(candidate) {
+                self.dominant = Some(candidate.clone());
+                self.recent.retain(|recent| recent != candidate);
+                self.recent.insert(0, candidate.clone());
+                messages.push(ServerMessage::ActiveSpeaker {
+                    stream_id: candidate.clone(),
+                });
+            }
+        }
+        // Silence is sent once, not every interval.
+        let speaking = !levels.is_empty();
+        if speaking || self.was_speaking {
+            messages.push(ServerMessage::AudioLevels { levels });
+        }
+        self.was_speaking = speaking;
+        messages
+    }
+}
+
+/// Linear amplitude of a level in -dBov.
+fn amplitude(level: u8) -> f64 {
+    10f64.powf(-f64::from(level) / 20.0)
+}
+
+/// Level in -dBov of a linear amplitude.
+fn level(amplitude: f64) -> u8 {
+    if amplitude <= 0.0 {
+        return SILENCE_LEVEL;
+    }
+    (-20.0 * amplitude.log10())
+        .round()
+        .clamp(0.0, f64::from(SILENCE_LEVEL)) as u8
+}
+
+/// Sends the speaker events of `group` to its members, and moves their last-N windows along
+/// with the dominant speaker, until the group is torn down.
+pub fn spawn_detection(group: Weak<Mutex<Group>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SPEAKER_INTERVAL).await;
+            let group = match group.upgrade() {
+                Some(group) => group,
+                None => break,
+            };
+            // A copy of the group's handles, so that the group isn't locked for the whole pass.
+            let group = group.lock().await.clone();
+            let messages = group.speakers.lock().unwrap().evaluate();
+            for message in &messages {
+                group.broadcast(message).await;
+            }
+            let speaker_changed = messages
+                .iter()
+                .any(|message| matches!(message, ServerMessage::ActiveSpeaker { .. }));
+            if speaker_changed {
+                group.apply_last_n().await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records one level per publisher, or nothing for silent ones, and ends the interval.
+    /// Returns the levels sent, if any, and the new dominant speaker, if it changed.
+    fn interval(
+        detector: &mut SpeakerDetector,
+        levels: &[(&str, u8)],
+    ) -> (Option<Vec<(String, u8)>>, Option<String>) {
+        for (stream_id, level) in levels {
+            detector.record(stream_id, *level);
+        }
+        let mut sent = None;
+        let mut dominant = None;
+        for message in detector.evaluate() {
+            match message {
+                ServerMessage::AudioLevels { levels } => {
+                    sent = Some(
+                        levels
+                            .into_iter()
+                            .map(|level| (level.stream_id, level.level))
+                            .collect(),
+                    )
+                }
+                ServerMessage::ActiveSpeaker { stream_id } => dominant = Some(stream_id),
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        (sent, dominant)
+    }
+
+    #[test]
+    fn converts_levels() {
+        for level in [0, 6, 20, 60, 126] {
+            assert_eq!(super::level(amplitude(level)), level);
+        }
+        assert_eq!(super::level(0.0), SILENCE_LEVEL);
+    }
+
+    #[test]
+    fn smooths_levels() {
+        let mut detector = SpeakerDetector::default();
+        // Two packets at 20 and 26 dB average to an amplitude of 0.075, 40% of which is taken.
+        detector.record("a", 20);
+        let (levels, _) = interval(&mut detector, &[("a", 26)]);
+        assert_eq!(levels, Some(vec![("a".to_owned(), 30)]));
+        let expected = [25, 23, 21, 21, 20, 20];
+        for level in expected {
+            let (levels, _) = interval(&mut detector, &[("a", 20)]);
+            assert_eq!(levels, Some(vec![("a".to_owned(), level)]));
+        }
+        // Silence fades out, and is sent once it fell below the speech threshold.
+        let mut intervals = 0;
+        loop {
+            intervals += 1;
+            match interval(&mut detector, &[]) {
+                (Some(levels), _) if levels.is_empty() => break,
+                (Some(_), _) => assert!(intervals < 10),
+                (None, _) => panic!("silence wasn't sent"),
+            }
+        }
+        assert_eq!(interval(&mut detector, &[]), (None, None));
+    }
+
+    #[test]
+    fn picks_the_dominant_speaker() {
+        let mut detector = SpeakerDetector::default();
+        assert_eq!(interval(&mut detector, &[("a", 20)]).1, None);
+        assert_eq!(
+            interval(&mut detector, &[("a", 20)]).1,
+            Some("a".to_owned())
+        );
+        for _ in 0..10 {
+            assert_eq!(interval(&mut detector, &[("a", 20)]).1, None);
+        }
+        assert_eq!(detector.recent(), ["a"]);
+
+        // Less than 6 dB louder than a speaking dominant speaker isn't enough.
+        for _ in 0..20 {
+            assert_eq!(interval(&mut detector, &[("a", 20), ("b", 16)]).1, None);
+        }
+        // Clearly louder for 2 intervals in a row is.
+        let mut intervals = 0;
+        let dominant = loop {
+            intervals += 1;
+            match interval(&mut detector, &[("a", 20), ("b", 6)]) {
+                (_, Some(dominant)) => break dominant,
+                _ => assert!(intervals < 5),
+            }
+        };
+        assert_eq!(dominant, "b");
+        assert_eq!(detector.recent(), ["b", "a"]);
+
+        // The dominant speaker stays while everybody is silent.
+        for _ in 0..20 {
+            assert_eq!(interval(&mut detector, &[]).1, None);
+        }
+        assert_eq!(interval(&mut detector, &[("a", 30)]).1, None);
+        assert_eq!(
+            interval(&mut detector, &[("a", 30)]).1,
+            Some("a".to_owned())
+        );
+        assert_eq!(detector.recent(), ["a", "b"]);
+    }
+
+    #[test]
+    fn forgets_publishers() {
+        let mut detector = SpeakerDetector::default();
+        interval(&mut detector, &[("a", 20)]);
+        interval(&mut detector, &[("a", 20)]);
+        detector.remove("a");
+        assert!(detector.recent().is_empty());
+        assert_eq!(interval(&mut detector, &[]).0, Some(vec![]));
+    }
+}
@@ -0,0 +1,390 @@
+//! Embedded TURN/STUN server for clients that can't reach the SFU directly.
+//!
+//! Clients get time-limited credentials per session (TURN REST API style: the username is the
+//! expiry timestamp, the password its HMAC with a secret only the server knows), so nothing
+//! long-lived has to be shipped to them.
+//!
+//! Besides UDP the server optionally listens on TCP, for networks letting nothing but TCP to
+//! port 443 out. Relays are UDP either way, only the hop between client and server changes.
+//! TURN over TLS isn't supported.
+use crate::config::TurnConfig;
+use crate::protocol::IceServer;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use webrtc::turn::auth::{generate_long_term_credentials, LongTermAuthHandler};
+use webrtc::turn::relay::relay_range::RelayAddressGeneratorRanges;
+use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc::turn::relay::RelayAddressGenerator;
+use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+use webrtc::turn::server::Server;
+use webrtc::util::vnet::net::Net;
+use webrtc::util::Conn;
+
+/// Size of a STUN message header, whose length field doesn't count it.
+const STUN_HEADER_SIZE: usize = 20;
+/// Size of a ChannelData header: channel number and length.
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+/// Messages read from TCP clients waiting for the server.
+const TCP_QUEUE_SIZE: usize = 256;
+
+pub struct TurnServer {
+    /// Kept alive for its listener, the server stops when dropped.
+    _server: Server,
+    urls: Vec<String>,
+    secret: String,
+    credential_ttl: Duration,
+}
+
+impl TurnServer {
+    pub async fn start(config: &TurnConfig) -> Result<TurnServer> {
+        // validate() made sure both parse
+        let listen = config.listen.parse::<SocketAddr>().unwrap();
+        let public_ip = config
+            .public_ip
+            .as_deref()
+            .unwrap()
+            .parse::<IpAddr>()
+            .unwrap();
+        let secret = config
+            .secret
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()));
+
+        let conn = UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("unable to bind turn listener {}", listen))?;
+        let host = SocketAddr::new(public_ip, listen.port());
+        let mut urls = vec![
+            format!("stun:{}", host),
+            format!("turn:{}?transport=udp", host),
+        ];
+        let mut conn_configs = vec![ConnConfig {
+            conn: Arc::new(conn),
+            relay_addr_generator: relay_addr_generator(config, listen.ip(), public_ip),
+        }];
+        if let Some(tcp_listen) = &config.tcp_listen {
+            // validate() made sure it parses
+            let tcp_listen = tcp_listen.parse::<SocketAddr>().unwrap();
+            let conn = TcpTurnConn::bind(tcp_listen, config.max_tcp_connections)
+                .await
+                .with_context(|| format!("unable to bind turn listener {}", tcp_listen))?;
+            info!("TURN server listening on TCP {}", tcp_listen);
+            urls.push(format!(
+                "turn:{}?transport=tcp",
+                SocketAddr::new(public_ip, tcp_listen.port())
+            ));
+            conn_configs.push(ConnConfig {
+                conn: Arc::new(conn),
+                relay_addr_generator: relay_addr_generator(config, tcp_listen.ip(), public_ip),
+            });
+        }
+        let server = Server::new(ServerConfig {
+            conn_configs,
+            realm: config.realm.clone(),
+            auth_handler: Arc::new(LongTermAuthHandler::new(secret.clone())),
+            channel_bind_timeout: Duration::from_secs(0),
+        })
+        .await
+        .context("unable to start turn server")?;
+        info!(
+            "TURN server listening on {} relaying via {}",
+            listen, public_ip
+        );
+
+        Ok(TurnServer {
+            _server: server,
+            urls,
+            secret,
+            credential_ttl: Duration::from_secs(config.credential_ttl),
+        })
+    }
+
+    /// Issues credentials valid for the configured time to live.
+    pub fn issue_credentials(&self) -> Result<IceServer> {
+        let (username, credential) =
+            generate_long_term_credentials(&self.secret, self.credential_ttl)?;
+        Ok(IceServer {
+            urls: self.urls.clone(),
+            username: Some(username),
+            credential: Some(credential),
+        })
+    }
+}
+
+/// Allocates the relays of the clients of one listener on `address`.
+fn relay_addr_generator(
+    config: &TurnConfig,
+    address: IpAddr,
+    public_ip: IpAddr,
+) -> Box<dyn RelayAddressGenerator + Send + Sync> {
+    match (config.relay_port_min, config.relay_port_max) {
+        (Some(min_port), Some(max_port)) => Box::new(RelayAddressGeneratorRanges {
+            relay_address: public_ip,
+            min_port,
+            max_port,
+            max_retries: 10,
+            address: address.to_string(),
+            net: Arc::new(Net::new(None)),
+        }),
+        _ => Box::new(RelayAddressGeneratorStatic {
+            relay_address: public_ip,
+            address: address.to_string(),
+            net: Arc::new(Net::new(None)),
+        }),
+    }
+}
+
+/// TCP listener presented to the TURN server as a packet connection: the STUN and ChannelData
+/// messages framed on every TCP connection (RFC 8656 section 3.1) are received as datagrams
+/// from the address of the client.
+struct TcpTurnConn {
+    local_addr: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    /// Open connections by client address, written to one message at a time.
+    clients: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>,
+}
+
+impl TcpTurnConn {
+    /// Listens on `addr`, with at most `max_connections` clients connected at a time.
+    async fn bind(addr: SocketAddr, max_connections: usize) -> io::Result<TcpTurnConn> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, incoming) = mpsc::channel(TCP_QUEUE_SIZE);
+        let clients = Arc::new(Mutex::new(HashMap::new()));
+        let accepted = clients.clone();
+        let connections = Arc::new(Semaphore::new(max_connections));
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Error accepting TURN connection: {}", err);
+                        continue;
+                    }
+                };
+                // Released once the connection is gone.
+                let permit = match connections.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        warn!(
+                            "Closing TURN connection from {}, {} are open already",
+                            addr, max_connections
+                        );
+                        continue;
+                    }
+                };
+                debug!("TURN connection from {}", addr);
+                let _ = stream.set_nodelay(true);
+                let (reader, writer) = stream.into_split();
+                accepted
+                    .lock()
+                    .await
+                    .insert(addr, Arc::new(Mutex::new(writer)));
+                let sender = sender.clone();
+                let clients = accepted.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(reader);
+                    loop {
+                        match read_message(&mut reader).await {
+                            Ok(message) => {
+                                if sender.send((message, addr)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(err) => {
+                                debug!("TURN connection from {} closed: {}", addr, err);
+                                break;
+                            }
+                        }
+                    }
+                    clients.lock().await.remove(&addr);
+                    drop(permit);
+                });
+            }
+        });
+        Ok(TcpTurnConn {
+            local_addr,
+            incoming: Mutex::new(incoming),
+            clients,
+        })
+    }
+}
+
+/// Reads the next STUN or ChannelData message, whichever the first two bits announce.
+async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut header = [0; CHANNEL_DATA_HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let size = match header[0] >> 6 {
+        0b00 => STUN_HEADER_SIZE + length,
+        // Padded to 4 bytes over TCP, unlike over UDP.
+        0b01 => CHANNEL_DATA_HEADER_SIZE + length.next_multiple_of(4),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "neither STUN nor ChannelData",
+            ))
+        }
+    };
+    let mut message = vec![0; size];
+    message[..CHANNEL_DATA_HEADER_SIZE].copy_from_slice(&header);
+    reader
+        .read_exact(&mut message[CHANNEL_DATA_HEADER_SIZE..])
+        .await?;
+    Ok(message)
+}
+
+#[async_trait]
+impl Conn for TcpTurnConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        loop {
+            let (message, addr) = incoming
+                .recv()
+                .await
+                .ok_or(webrtc::util::Error::ErrClosedListener)?;
+            // Truncated it would be parsed as a corrupt message, the framing of the connection
+            // is intact without it.
+            if message.len() > buf.len() {
+                warn!(
+                    "Dropping TURN message of {} bytes from {}, larger than {} bytes",
+                    message.len(),
+                    addr,
+                    buf.len()
+                );
+                continue;
+            }
+            buf[..message.len()].copy_from_slice(&message);
+            return Ok((message.len(), addr));
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::NotConnected).into())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let writer = self
+            .clients
+            .lock()
+            .await
+            .get(&target)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        writer.lock().await.write_all(buf).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_stun_messages_by_their_length() {
+        // Binding request with a 4 byte attribute, followed by the next message.
+        let mut stream: &[u8] = &[
+            0x00, 0x01, 0x00, 0x04, 0x21, 0x12, 0xa4, 0x42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+            0x80, 0x22, 0x00, 0x00, 0xff,
+        ];
+        let message = read_message(&mut stream).await.unwrap();
+        assert_eq!(message.len(), STUN_HEADER_SIZE + 4);
+        assert_eq!(stream, &[0xff]);
+    }
+
+    #[tokio::test]
+    async fn reads_channel_data_with_its_padding() {
+        let mut stream: &[u8] = &[0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0, 0xff];
+        let message = read_message(&mut stream).await.unwrap();
+        assert_eq!(message, [0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(stream, &[0xff]);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_messages() {
+        let mut stream: &[u8] = &[0x80, 0x00, 0x00, 0x00];
+        let err = read_message(&mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn drops_messages_larger_than_the_buffer() {
+        let (sender, incoming) = mpsc::channel(TCP_QUEUE_SIZE);
+        let conn = TcpTurnConn {
+            local_addr: "127.0.0.1:3478".parse().unwrap(),
+            incoming: Mutex::new(incoming),
+            clients: Arc::default(),
+        };
+        let addr: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        sender.send((vec![1; 12], addr)).await.unwrap();
+        sender.send((vec![2; 8], addr)).await.unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(conn.recv_from(&mut buf).await.unwrap(), (8, addr));
+        assert_eq!(buf, [2; 8]);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_over_the_limit() {
+        let conn = TcpTurnConn::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let mut first = tokio::net::TcpStream::connect(conn.local_addr)
+            .await
+            .unwrap();
+        let mut second = tokio::net::TcpStream::connect(conn.local_addr)
+            .await
+            .unwrap();
+        let mut byte = [0; 1];
+        assert_eq!(second.read(&mut byte).await.unwrap(), 0);
+        // A binding request on the first one still makes it through.
+        first
+            .write_all(&[
+                0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+                12,
+            ])
+            .await
+            .unwrap();
+        let mut buf = [0; 1500];
+        let (size, addr) = conn.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, STUN_HEADER_SIZE);
+        assert_eq!(addr, first.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_messages() {
+        let mut stream: &[u8] = &[0x40, 0x00, 0x00, 0x08, 1, 2];
+        let err = read_message(&mut stream).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
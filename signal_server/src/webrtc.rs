@@ -1,212 +1,617 @@
+use crate::bwe::{BandwidthEstimator, Estimate, ALLOCATION_INTERVAL, MIN_BITRATE};
+use crate::codecs::{codecs_match, is_sending, media_codecs, register_codecs, GroupCodecs};
+use crate::config::Config;
+use crate::dump::{DumpedLayer, RtpDump};
+use crate::forward::{DownTrack, DownTrackStats, Track};
+use crate::keyframe::KeyframeRequester;
+use crate::protocol::{send_message, ClientSender, ErrorCode, ServerMessage, SignalError};
+use crate::role::Role;
+use crate::speaker::{audio_level, SpeakerDetector};
 use crate::Group;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
 use serde::Serialize;
-use tokio::sync::mpsc;
-use tokio::{sync::Mutex, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::{watch, Mutex},
+    time::Duration,
+};
 use uuid::Uuid;
-use warp::ws::Message;
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
 use webrtc::ice_transport::ice_gatherer_state::RTCIceGathererState;
-use webrtc::Error;
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
+        interceptor_registry::{configure_rtcp_reports, configure_twcc},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+        APIBuilder, API,
     },
     ice_transport::{
         ice_candidate::RTCIceCandidate, ice_candidate::RTCIceCandidateInit,
         ice_server::RTCIceServer,
     },
-    interceptor::registry::Registry,
+    interceptor::{nack::generator::Generator, registry::Registry},
     peer_connection::{
-        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
-        signaling_state::RTCSignalingState, RTCPeerConnection,
+        configuration::RTCConfiguration, offer_answer_options::RTCOfferOptions,
+        peer_connection_state::RTCPeerConnectionState, signaling_state::RTCSignalingState,
+        RTCPeerConnection,
+    },
+    rtcp::{
+        payload_feedbacks::{
+            full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+        },
+        transport_feedbacks::transport_layer_nack::TransportLayerNack,
     },
-    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
-    rtp_transceiver::{rtp_codec::RTPCodecType, rtp_receiver::RTCRtpReceiver},
-    track::{
-        track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
-        track_remote::TrackRemote,
+    rtp_transceiver::{
+        rtp_codec::{
+            RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability,
+            RTPCodecType,
+        },
+        rtp_receiver::RTCRtpReceiver,
+        rtp_sender::RTCRtpSender,
     },
+    sdp::extmap::{AUDIO_LEVEL_URI, SDES_MID_URI, SDES_RTP_STREAM_ID_URI},
+    track::{track_local::TrackLocal, track_remote::TrackRemote},
 };
 
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Weak};
-use tracing::{debug, warn};
+use std::time::Instant;
+use tracing::{debug, info, warn};
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
 #[derive(Debug, Clone)]
 pub struct WebRTCConnection {
     pub peer_connection: Arc<RTCPeerConnection>,
-    sender: Arc<Box<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>>,
+    sender: ClientSender,
     group: Arc<Mutex<Group>>,
+    group_codecs: Arc<Mutex<GroupCodecs>>,
+    speakers: Arc<std::sync::Mutex<SpeakerDetector>>,
     tracks: Arc<Mutex<HashMap<String, Arc<Track>>>>,
+    // Tracks forwarded to this peer, keyed by track id.
+    subscriptions: Arc<Mutex<HashMap<String, Subscription>>>,
+    // Bandwidth towards this peer, estimated from the feedback on the forwarded tracks.
+    bwe: Arc<std::sync::Mutex<BandwidthEstimator>>,
+    last_n: Arc<std::sync::Mutex<LastN>>,
+    negotiation: Arc<Mutex<NegotiationState>>,
+    // Set once the connection is closed, stops the forwarding of its tracks.
+    closed: Arc<watch::Sender<bool>>,
+    // Packets received on the connection are dumped here for debugging when set.
+    dump: Option<Arc<RtpDump>>,
+    // WHIP publishers receive nothing and can't be sent offers, see `whip`.
+    publish_only: bool,
+    role: Role,
     id: Uuid,
+    // Identity of the client, given to the tracks it publishes.
+    participant: String,
 }
 
-#[derive(Serialize)]
-struct RTCSessionDescriptionInit {
-    sdp: RTCSessionDescription,
+// Offers and answers are applied one at a time while holding this state. The server takes the
+// impolite role of perfect negotiation: a client offer colliding with one of ours is ignored and
+// the client, being polite, is expected to roll back and answer ours instead.
+#[derive(Debug, Default)]
+struct NegotiationState {
+    // Set when negotiation was needed while an offer/answer exchange was in flight.
+    renegotiation_pending: bool,
+    // Set when the next offer has to restart ICE.
+    ice_restart_pending: bool,
+    // Remote candidates received before the remote description they belong to.
+    pending_candidates: Vec<RTCIceCandidateInit>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Track {
-    track: Arc<TrackLocalStaticRTP>,
+/// Remote candidates buffered per session before they are rejected.
+pub const MAX_PENDING_CANDIDATES: usize = 64;
+
+/// How long a disconnected peer gets to recover on its own before ICE is restarted.
+const ICE_RESTART_DELAY: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Serialize)]
+pub struct PeerStats {
+    pub id: String,
+    pub estimate: Estimate,
+    pub subscriptions: Vec<DownTrackStats>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    track: Arc<Track>,
+    down_track: Arc<DownTrack>,
+    rtp_sender: Arc<RTCRtpSender>,
+    subscribed: Instant,
+}
+
+/// Video a peer receives at most, see `WebRTCConnection::apply_last_n`.
+#[derive(Debug, Clone, Default)]
+pub struct LastN {
+    /// Video tracks forwarded, all when unset.
+    pub limit: Option<usize>,
+    /// Stream ids of the publishers whose video goes first, in this order.
+    pub pinned: Vec<String>,
+}
+
+/// Media kinds the remote side of `sdp` offers to send.
+pub fn published_kinds(sdp: &str) -> Result<Vec<RTPCodecType>> {
+    let description = RTCSessionDescription::offer(sdp.to_owned())?.unmarshal()?;
+    let kinds = description
+        .media_descriptions
+        .iter()
+        .filter(|media| is_sending(media))
+        .map(|media| RTPCodecType::from(media.media_name.media.as_str()))
+        .filter(|kind| *kind != RTPCodecType::Unspecified)
+        .collect();
+    Ok(kinds)
+}
+
+fn not_forwarded(track_id: &str) -> SignalError {
+    SignalError::new(
+        ErrorCode::UnknownTrack,
+        format!("track {} is not forwarded to you", track_id),
+    )
+}
+
+/// What the tracks received from a peer need to know about it.
+#[derive(Clone)]
+struct Publisher {
+    keyframes: Arc<KeyframeRequester>,
+    group: Arc<Mutex<Group>>,
+    speakers: Arc<std::sync::Mutex<SpeakerDetector>>,
     id: String,
+    participant: String,
+    peer_connection: Weak<RTCPeerConnection>,
+    tracks: Weak<Mutex<HashMap<String, Arc<Track>>>>,
+    role: Role,
+    closed: watch::Receiver<bool>,
+    dump: Option<Arc<RtpDump>>,
 }
 
 fn handle_track(
     remote_track: Option<Arc<TrackRemote>>,
-    p2: &Weak<RTCPeerConnection>,
-    group: &Arc<Mutex<Group>>,
-    peer_identity: &String,
-    tracks: &Weak<Mutex<HashMap<String, Arc<Track>>>>,
+    receiver: Option<Arc<RTCRtpReceiver>>,
+    publisher: &Publisher,
 ) {
-    let peer_identity2 = peer_identity.clone();
+    let Publisher {
+        keyframes,
+        group,
+        speakers,
+        id: peer_identity,
+        participant,
+        peer_connection,
+        tracks,
+        role,
+        closed,
+        dump,
+    } = publisher;
+    let role = *role;
+    let peer_identity2 = peer_identity.to_owned();
+    if !role.can_publish() {
+        warn!(
+            "Ignoring track published by {} as {:?}",
+            peer_identity, role
+        );
+        return;
+    }
     if let Some(track) = remote_track {
-        let media_ssrc = track.ssrc();
-        // write rtcps on interval as there isn't a rtcp event
-        let pc3 = p2.clone();
-        tokio::spawn(async move {
-            let mut result = Result::<usize, webrtc::Error>::Ok(0);
-            while result.is_ok() {
-                let timeout = tokio::time::sleep(Duration::from_secs(3));
-                tokio::pin!(timeout);
-
-                tokio::select! {
-                  _ = timeout.as_mut() =>{
-                    result = pc3.upgrade().unwrap().write_rtcp(&[Box::new(PictureLossIndication{
-                      sender_ssrc: 0,
-                      media_ssrc,
-                    })]).await.map_err(Into::into);
-                  }
-                };
-            }
-        });
         // write rtps on event
         let track2 = track.clone();
         let group = group.clone();
         let tracks2 = tracks.clone();
+        let keyframes = keyframes.clone();
+        let speakers = speakers.clone();
+        let mut closed = closed.clone();
+        let dump = dump.clone();
+        let peer_connection = peer_connection.clone();
+        let participant = participant.clone();
         tokio::spawn(async move {
             let stream_id = peer_identity2.clone();
-            let track_id = format!("{}_{}", stream_id, track2.kind().to_string());
-            let local_track = Arc::new(TrackLocalStaticRTP::new(
-                track2.codec().await.capability,
-                track_id.clone(),
-                stream_id, // FIXME: mabye this should be changed so the stream of
-                           // video-audio is different for each pair
-            ));
-            debug!(
-                "Adding local track {:?} to group {:?}\n",
-                local_track, group
-            );
-            let track = Track {
-                track: local_track,
-                id: track_id,
+            // Tracks are told apart by their transceiver, the simulcast layers of one share it.
+            let mid = match receiver_mid(&peer_connection, &receiver).await {
+                Some(mid) => mid,
+                None => track2.ssrc().to_string(),
             };
-            let id = track.id.clone();
-            let track = Arc::new(track.clone());
-            if let Some(tracks3) = tracks2.upgrade() {
-                tracks3.lock().await.insert(id, track.clone());
+            let track_id = format!("{}_{}", stream_id, mid);
+            let rid = track2.rid().to_owned();
+            let codec = track2.codec().await.capability;
+            // Each simulcast layer arrives as a remote track of its own, the receiver knows them
+            // all from the offer.
+            let rids = match &receiver {
+                Some(receiver) => receiver
+                    .tracks()
+                    .await
+                    .iter()
+                    .map(|track| track.rid().to_owned())
+                    .collect(),
+                None => vec![],
+            };
+            let tracks3 = match tracks2.upgrade() {
+                Some(tracks3) => tracks3,
+                None => return,
+            };
+            let (track, created) = {
+                let mut tracks3 = tracks3.lock().await;
+                match tracks3.get(&track_id) {
+                    Some(track) => (track.clone(), false),
+                    None => {
+                        let track = Arc::new(
+                            Track::new(
+                                track_id.clone(),
+                                stream_id,
+                                role,
+                                track2.kind(),
+                                codec.clone(),
+                                rids,
+                                keyframes,
+                            )
+                            .with_participant(participant),
+                        );
+                        tracks3.insert(track_id, track.clone());
+                        (track, true)
+                    }
+                }
+            };
+            drop(tracks3);
+            debug!("Track {} receives layer {:?}", track.id(), rid);
+            if !track.add_layer(&rid, track2.ssrc()) {
+                warn!(
+                    "Ignoring ssrc {} of track {}, layer {:?} is received already",
+                    track2.ssrc(),
+                    track.id(),
+                    rid
+                );
+                return;
+            }
+            if created {
+                pin_codec(&group, track.kind(), &codec).await;
+                debug!("Adding track {:?} to group {:?}", track.id(), group);
+                group.lock().await.notify_track(&track).await;
+            }
+            // Audio levels tell the group who is speaking, see `speaker`.
+            let level_extension = match track.kind() {
+                RTPCodecType::Audio => track2
+                    .params()
+                    .await
+                    .header_extensions
+                    .iter()
+                    .find(|extension| extension.uri == AUDIO_LEVEL_URI)
+                    .map(|extension| extension.id as u8),
+                _ => None,
+            };
+            if let Some(dump) = &dump {
+                dump.add_layer(DumpedLayer {
+                    ssrc: track2.ssrc(),
+                    track_id: track.id().to_owned(),
+                    kind: track.kind().to_string(),
+                    rid: rid.clone(),
+                    role,
+                    mime_type: codec.mime_type.clone(),
+                    clock_rate: codec.clock_rate,
+                    channels: codec.channels,
+                    sdp_fmtp_line: codec.sdp_fmtp_line.clone(),
+                    audio_level_extension: level_extension,
+                });
             }
-            group.lock().await.notify_track(&track).await;
-            while let Ok((rtp, _)) = track2.read_rtp().await {
-                if let Err(err) = track.track.write_rtp(&rtp).await {
-                    if Error::ErrClosedPipe != err {
-                        warn!("output track write_rtp got error: {} and break", err);
-                        break;
-                    } else {
-                        warn!("output track write_rtp got error: {}", err);
+            loop {
+                let rtp = tokio::select! {
+                    result = track2.read_rtp() => match result {
+                        Ok((rtp, _)) => rtp,
+                        Err(err) => {
+                            debug!("Remote track {} layer {:?} ended: {}", track.id(), rid, err);
+                            break;
+                        }
+                    },
+                    _ = closed.changed() => break,
+                };
+                if let Some(dump) = &dump {
+                    dump.rtp(&rtp);
+                }
+                if let Some(level) = level_extension.and_then(|id| audio_level(&rtp, id)) {
+                    if !track.is_muted() {
+                        speakers.lock().unwrap().record(track.publisher_id(), level);
                     }
                 }
+                track.forward(&rid, &rtp).await;
+            }
+            if track.remove_layer(&rid) == 0 {
+                if track.kind() == RTPCodecType::Audio {
+                    speakers.lock().unwrap().remove(track.publisher_id());
+                }
+                unpublish(&group, &tracks2, &track).await;
             }
         });
         debug!("Got track {:?}", track);
     };
 }
 
+/// The mid of the transceiver `receiver` belongs to.
+async fn receiver_mid(
+    peer_connection: &Weak<RTCPeerConnection>,
+    receiver: &Option<Arc<RTCRtpReceiver>>,
+) -> Option<String> {
+    let peer_connection = peer_connection.upgrade()?;
+    let receiver = receiver.as_ref()?;
+    for transceiver in peer_connection.get_transceivers().await {
+        let owns = match transceiver.receiver().await {
+            Some(owned) => Arc::ptr_eq(&owned, receiver),
+            None => false,
+        };
+        if owns {
+            let mid = transceiver.mid().await;
+            return (!mid.is_empty()).then_some(mid);
+        }
+    }
+    None
+}
+
+/// Withdraws `track` from the subscribers of the group once its publisher stopped sending it,
+/// unless it was withdrawn already.
+async fn unpublish(
+    group: &Arc<Mutex<Group>>,
+    tracks: &Weak<Mutex<HashMap<String, Arc<Track>>>>,
+    track: &Arc<Track>,
+) {
+    let removed = match tracks.upgrade() {
+        Some(tracks) => tracks.lock().await.remove(track.id()).is_some(),
+        None => false,
+    };
+    if removed {
+        debug!("Unpublishing track {}", track.id());
+        group
+            .lock()
+            .await
+            .remove_tracks(std::slice::from_ref(track))
+            .await;
+    }
+}
+
+/// Pins `codec` for `kind` in the group unless a codec was pinned already.
+pub async fn pin_codec(
+    group: &Arc<Mutex<Group>>,
+    kind: RTPCodecType,
+    codec: &RTCRtpCodecCapability,
+) {
+    let codecs = group.lock().await.codecs.clone();
+    let mut codecs = codecs.lock().await;
+    let pinned = match codecs.pin(kind, codec) {
+        Some(pinned) => pinned,
+        None => return,
+    };
+    debug!("Group uses {} {}", pinned.mime_type, pinned.sdp_fmtp_line);
+    if !codecs_match(pinned, codec) {
+        warn!(
+            "Track published with {} while the group uses {}, subscribers won't decode it",
+            codec.mime_type, pinned.mime_type
+        );
+    }
+}
+
+/// Builds the API every peer connection is created from, once at startup.
+///
+/// Peer connections copy the media engine and build their own interceptor chain from the
+/// registry, so sharing the API shares configuration only.
+pub async fn build_api(config: &Config) -> Result<API> {
+    let mut m = MediaEngine::default();
+    register_codecs(&mut m, &config.rtc_codecs())?;
+    m.register_header_extension(
+        RTCRtpHeaderExtensionCapability {
+            uri: AUDIO_LEVEL_URI.to_owned(),
+        },
+        RTPCodecType::Audio,
+        None,
+    )?;
+    // Needed to tell the simulcast layers of a publisher apart.
+    for uri in [SDES_MID_URI, SDES_RTP_STREAM_ID_URI] {
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: uri.to_owned(),
+            },
+            RTPCodecType::Video,
+            None,
+        )?;
+    }
+
+    let mut registry = Registry::new();
+
+    // The default interceptors, except for the NACK responder: NACKs of subscribers are answered
+    // from the packets each forwarded track buffers, see `forward`. The codecs declare the NACK
+    // feedback themselves.
+    registry.add(Box::new(Generator::builder()));
+    registry = configure_rtcp_reports(registry);
+    // TWCC both ways, subscribers report on the forwarded packets with it too, see `bwe`.
+    registry = configure_twcc(registry, &mut m)?;
+
+    let mut settings = SettingEngine::default();
+    settings.set_network_types(config.webrtc.network_types.clone());
+    settings.set_ice_multicast_dns_mode(config.webrtc.mdns.into());
+    if let Some(port) = config.webrtc.udp_mux_port {
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .await
+            .with_context(|| format!("unable to bind udp mux port {}", port))?;
+        info!("Multiplexing peer connections over udp port {}", port);
+        let mux = UDPMuxDefault::new(UDPMuxParams::new(socket));
+        settings.set_udp_network(UDPNetwork::Muxed(mux));
+    } else if let (Some(min), Some(max)) = (config.webrtc.udp_port_min, config.webrtc.udp_port_max)
+    {
+        settings.set_udp_network(UDPNetwork::Ephemeral(EphemeralUDP::new(min, max)?));
+    }
+    if !config.webrtc.nat_1to1_ips.is_empty() {
+        settings.set_nat_1to1_ips(
+            config.webrtc.nat_1to1_ips.clone(),
+            RTCIceCandidateType::Host,
+        );
+    }
+
+    Ok(APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(settings)
+        .build())
+}
+
+/// Codecs peer connections created from `api` negotiate, read from an offer of a throwaway one.
+pub async fn supported_codecs(api: &API) -> Result<Vec<RTCRtpCodecCapability>> {
+    let peer_connection = api.new_peer_connection(RTCConfiguration::default()).await?;
+    for kind in [RTPCodecType::Audio, RTPCodecType::Video] {
+        peer_connection.add_transceiver_from_kind(kind, &[]).await?;
+    }
+    let offer = peer_connection.create_offer(None).await;
+    peer_connection.close().await?;
+    let description = offer?.unmarshal()?;
+    Ok(description
+        .media_descriptions
+        .iter()
+        .flat_map(|media| media_codecs(&description, media))
+        .collect())
+}
+
 impl WebRTCConnection {
     pub async fn new(
-        sender: Arc<Box<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>>,
+        sender: ClientSender,
         group: Arc<Mutex<Group>>,
-    ) -> Result<Box<WebRTCConnection>, String> {
+        role: Role,
+        api: &API,
+        ice_servers: Vec<RTCIceServer>,
+        last_n: LastN,
+        rtp_dump_directory: Option<&Path>,
+    ) -> Result<Box<WebRTCConnection>> {
+        let (group_codecs, speakers) = {
+            let group = group.lock().await;
+            (group.codecs.clone(), group.speakers.clone())
+        };
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
+            ice_servers,
             ..Default::default()
         };
 
-        let mut m = MediaEngine::default();
-        // Setup the codecs you want to use.
-        // We'll use a VP8 and Opus but you can also define your own
-        m.register_default_codecs().unwrap();
-
-        let mut registry = Registry::new();
-
-        // Use the default set of Interceptors
-        registry = register_default_interceptors(registry, &mut m).unwrap();
-
-        // Create the API object with the MediaEngine
-        let api = APIBuilder::new()
-            .with_media_engine(m)
-            .with_interceptor_registry(registry)
-            .build();
         let peer_connection = api.new_peer_connection(config).await;
         let peer_connection = match peer_connection {
             Ok(conn) => conn,
             Err(error) => {
-                return Err(format!(
+                return Err(anyhow!(
                     "Unable to create new peer connection error:{}",
                     error
                 ));
             }
         };
         let peer_connection = Arc::new(peer_connection);
-        peer_connection
-            .add_transceiver_from_kind(RTPCodecType::Audio, &[])
-            .await
-            .expect("Failed audio");
-        peer_connection
-            .add_transceiver_from_kind(RTPCodecType::Video, &[])
-            .await
-            .expect("Failed video");
+        for kind in [RTPCodecType::Audio, RTPCodecType::Video] {
+            if let Err(err) = peer_connection.add_transceiver_from_kind(kind, &[]).await {
+                if let Err(close_err) = peer_connection.close().await {
+                    warn!("Error closing peer connection {:?}", close_err);
+                }
+                return Err(anyhow!("Unable to add {} transceiver: {}", kind, err));
+            }
+        }
+
+        let id = Uuid::new_v4();
+        let dump = rtp_dump_directory.and_then(|directory| {
+            let name = format!("{}_{}", Utc::now().format("%Y%m%dT%H%M%SZ"), id);
+            match RtpDump::create(directory, &name) {
+                Ok(dump) => {
+                    info!(
+                        "Dumping RTP of peer connection {} to {}",
+                        id,
+                        dump.path().display()
+                    );
+                    Some(Arc::new(dump))
+                }
+                Err(err) => {
+                    warn!("Unable to dump RTP of peer connection {}: {}", id, err);
+                    None
+                }
+            }
+        });
 
         let res = Box::new(WebRTCConnection {
             peer_connection,
             sender,
             group: group.clone(),
+            group_codecs,
+            speakers,
             tracks: Arc::new(Mutex::new(HashMap::new())),
-            id: Uuid::new_v4(),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            bwe: Arc::new(std::sync::Mutex::new(BandwidthEstimator::default())),
+            last_n: Arc::new(std::sync::Mutex::new(last_n)),
+            negotiation: Arc::new(Mutex::new(NegotiationState::default())),
+            closed: Arc::new(watch::channel(false).0),
+            dump,
+            publish_only: false,
+            role,
+            participant: id.to_string(),
+            id,
         });
         Ok(res)
     }
 
     pub fn get_id(&self) -> String {
-        return self.id.to_string();
+        self.id.to_string()
+    }
+
+    /// Makes the connection only publish, before `setup_callbacks`.
+    pub fn set_publish_only(&mut self) {
+        self.publish_only = true;
+    }
+
+    /// Sets the identity of the client, see `Client::identity`, before `setup_callbacks`.
+    pub fn set_participant(&mut self, participant: &str) {
+        self.participant = participant.to_owned();
     }
 
     pub async fn setup_callbacks(&self) {
         let ice_sender = self.sender.clone();
 
-        let p2 = Arc::downgrade(&self.peer_connection);
-        let group = self.group.clone();
-        let peer_identity = self.get_id();
-        let tracks = Arc::downgrade(&self.tracks);
+        let publisher = Publisher {
+            keyframes: Arc::new(KeyframeRequester::new(Arc::downgrade(
+                &self.peer_connection,
+            ))),
+            group: self.group.clone(),
+            speakers: self.speakers.clone(),
+            id: self.get_id(),
+            participant: self.participant.clone(),
+            peer_connection: Arc::downgrade(&self.peer_connection),
+            tracks: Arc::downgrade(&self.tracks),
+            role: self.role,
+            closed: self.closed.subscribe(),
+            dump: self.dump.clone(),
+        };
         self.peer_connection.on_track(Box::new(
             move |remote_track: Option<Arc<TrackRemote>>,
-                  _rtp_receiver: Option<Arc<RTCRtpReceiver>>| {
-                handle_track(remote_track, &p2, &group, &peer_identity, &tracks);
+                  rtp_receiver: Option<Arc<RTCRtpReceiver>>| {
+                handle_track(remote_track, rtp_receiver, &publisher);
                 Box::pin(async {})
             },
         ));
 
         let webrtc_connection = self.clone();
+        let webrtc_connection_signaling = self.clone();
+        let webrtc_connection_state = self.clone();
+
+        let webrtc_connection_bwe = self.clone();
+        let mut closed = self.closed.subscribe();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(ALLOCATION_INTERVAL) => {}
+                    _ = closed.changed() => break,
+                }
+                webrtc_connection_bwe.allocate_layers().await;
+                webrtc_connection_bwe.send_bitrate_hint().await;
+            }
+        });
 
         self.peer_connection.on_signaling_state_change(Box::new(
             move |state: RTCSignalingState| {
                 // TODO: disconnect
-                debug!("Signaling state change {}", state.to_string());
+                debug!("Signaling state change {}", state);
+                if state == RTCSignalingState::Stable {
+                    let webrtc_connection2 = webrtc_connection_signaling.clone();
+                    tokio::spawn(async move {
+                        webrtc_connection2.renegotiate_if_pending().await;
+                    });
+                }
                 Box::pin(async move {})
             },
         ));
@@ -238,11 +643,26 @@ impl WebRTCConnection {
             .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
                 debug!("Peer Connection State has changed: {}", s);
 
-                if s == RTCPeerConnectionState::Failed {
-                    // Wait until PeerConnection has had no network activity for 30 seconds or another failure. It may be reconnected using an ICE Restart.
-                    // Use webrtc.PeerConnectionStateDisconnected if you are interested in detecting faster timeout.
-                    // Note that the PeerConnection may come back from PeerConnectionStateDisconnected.
-                    debug!("Peer Connection has gone to failed exiting");
+                // The peer most likely changed networks, e.g. from Wi-Fi to LTE. A disconnected
+                // connection may come back by itself, a failed one only through an ICE restart.
+                let webrtc_connection2 = webrtc_connection_state.clone();
+                match s {
+                    RTCPeerConnectionState::Disconnected => {
+                        tokio::spawn(async move {
+                            tokio::time::sleep(ICE_RESTART_DELAY).await;
+                            if webrtc_connection2.peer_connection.connection_state()
+                                == RTCPeerConnectionState::Disconnected
+                            {
+                                webrtc_connection2.restart_ice().await;
+                            }
+                        });
+                    }
+                    RTCPeerConnectionState::Failed => {
+                        tokio::spawn(async move {
+                            webrtc_connection2.restart_ice().await;
+                        });
+                    }
+                    _ => {}
                 }
 
                 Box::pin(async {})
@@ -253,13 +673,10 @@ impl WebRTCConnection {
                 // forward candidate
                 debug!("WEBRTC pre candidate");
                 if let Some(candidate) = candidate {
-                    debug!("WEBRTC candidate {}", candidate.to_string());
-                    let ice_message = serde_json::to_string(&candidate).unwrap();
-                    let msg = warp::ws::Message::text(ice_message);
-                    debug!("WEBRTC candidate msg {:?}", msg);
-                    match ice_sender.as_ref().send(Ok(msg)) {
-                        Err(err) => warn!("Error sending ice candidate {:?}", err),
-                        _ => {}
+                    debug!("WEBRTC candidate {}", candidate);
+                    match candidate.to_json() {
+                        Ok(ice) => send_message(&ice_sender, &ServerMessage::Ice { ice }),
+                        Err(err) => warn!("Error serializing ice candidate {:?}", err),
                     }
                 }
                 Box::pin(async {}) // we don't need to return anything, just send the candidate
@@ -267,131 +684,497 @@ impl WebRTCConnection {
         ));
     }
 
-    pub async fn process_offer(&self, offer: String) {
+    /// Answers a client offer. Returns `false` when the offer was ignored because it collided
+    /// with an offer of ours.
+    pub async fn process_offer(&self, offer: String) -> Result<bool> {
         debug!("Offer before RTCSessionDescription is {:?}", offer);
 
-        let description = match serde_json::from_str::<RTCSessionDescription>(&offer.as_str()) {
-            Ok(value) => value,
-            Err(err) => {
-                warn!("Error creating session for offer {:?}", err);
-                RTCSessionDescription::default()
-            }
-        };
-        let res = self
-            .peer_connection
+        let mut negotiation = self.negotiation.lock().await;
+        let state = self.peer_connection.signaling_state();
+        if state != RTCSignalingState::Stable {
+            debug!(
+                "Ignoring colliding offer for {} in signaling state {}",
+                self.get_id(),
+                state
+            );
+            return Ok(false);
+        }
+        let description = RTCSessionDescription::offer(offer)?;
+        self.peer_connection
             .set_remote_description(description)
-            .await;
-        match res {
-            Ok(_value) => debug!("Successfully added offer remote description"),
-            Err(err) => warn!("Set description error {:?}", err),
-        };
+            .await?;
+        debug!("Successfully added offer remote description");
+        self.add_pending_candidates(&mut negotiation).await;
+        self.apply_codec_preferences().await;
         // https://stackoverflow.com/questions/38036552/rtcpeerconnection-onicecandidate-not-fire
-        let answer = match self.peer_connection.create_answer(None).await {
-            Ok(a) => a,
-            Err(err) => panic!("{}", err),
-        };
+        let answer = self.peer_connection.create_answer(None).await?;
         debug!("Answer is {:?}", answer);
-        let local_res = self
-            .peer_connection
+        self.peer_connection
             .set_local_description(answer.clone())
-            .await;
-        match local_res {
-            Ok(value) => debug!("Set local description {:?}", value),
-            Err(err) => warn!("Set local description error {:?}", err),
-        };
+            .await?;
 
-        let answer = RTCSessionDescriptionInit { sdp: answer };
-        let answer = serde_json::to_string(&answer).unwrap();
-        let msg = warp::ws::Message::text(answer);
-        match self.sender.send(Ok(msg)) {
-            Err(err) => warn!("Error sending answer {:?}", err),
-            _ => {}
-        }
+        send_message(&self.sender, &ServerMessage::Answer { sdp: answer.sdp });
+        Ok(true)
     }
 
-    pub async fn process_answer(&self, answer: String) {
-        let description = match serde_json::from_str::<RTCSessionDescription>(&answer.as_str()) {
-            Ok(value) => value,
-            Err(err) => {
-                warn!("Error creating session description for answer {:?}", err);
-                RTCSessionDescription::default()
-            }
-        };
-        match self
-            .peer_connection
+    pub async fn process_answer(&self, answer: String) -> Result<()> {
+        let mut negotiation = self.negotiation.lock().await;
+        let state = self.peer_connection.signaling_state();
+        if state != RTCSignalingState::HaveLocalOffer {
+            return Err(anyhow!(
+                "unexpected answer in signaling state {}, no offer is pending",
+                state
+            ));
+        }
+        let description = RTCSessionDescription::answer(answer)?;
+        self.peer_connection
             .set_remote_description(description)
-            .await
-        {
-            Ok(value) => debug!("Set remote description {:?}", value),
-            Err(err) => warn!("Set remote description error {:?}", err),
-        };
+            .await?;
+        debug!("Set remote description");
+        self.add_pending_candidates(&mut negotiation).await;
+        Ok(())
     }
 
-    pub async fn process_ice_candidate(&self, candidate: String) {
-        let candidate = match serde_json::from_str::<RTCIceCandidateInit>(candidate.as_str()) {
-            Ok(value) => value,
-            Err(err) => {
-                warn!("Error adding ice_candidate {:?}", err);
-                RTCIceCandidateInit::default()
-            }
-        };
-        match self.peer_connection.add_ice_candidate(candidate).await {
-            Ok(_value) => {
-                debug!("Successfully added ice candidate");
+    /// Adds a remote candidate, or buffers it until the remote description is set.
+    pub async fn process_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        let mut negotiation = self.negotiation.lock().await;
+        if self.peer_connection.remote_description().await.is_none() {
+            if negotiation.pending_candidates.len() >= MAX_PENDING_CANDIDATES {
+                return Err(anyhow!(
+                    "more than {} candidates before the offer",
+                    MAX_PENDING_CANDIDATES
+                ));
             }
-            Err(err) => {
-                warn!("Error adding ice candidate {:?}", err);
+            debug!("Buffering ice candidate until the remote description is set");
+            negotiation.pending_candidates.push(candidate);
+            return Ok(());
+        }
+        self.peer_connection.add_ice_candidate(candidate).await?;
+        debug!("Successfully added ice candidate");
+        Ok(())
+    }
+
+    async fn add_pending_candidates(&self, negotiation: &mut NegotiationState) {
+        for candidate in negotiation.pending_candidates.drain(..) {
+            if let Err(err) = self.peer_connection.add_ice_candidate(candidate).await {
+                warn!(
+                    "Dropping buffered ice candidate for {}: {}",
+                    self.get_id(),
+                    err
+                );
             }
         }
     }
 
-    pub async fn add_remote_track(&mut self, track: &Track) {
-        match self.peer_connection.add_track(track.track.clone()).await {
+    pub async fn close(&self) {
+        let _ = self.closed.send(true);
+        for (_, subscription) in self.subscriptions.lock().await.drain() {
+            subscription.track.unsubscribe(&self.get_id());
+        }
+        if let Err(err) = self.peer_connection.close().await {
+            warn!("Error closing peer connection {}: {:?}", self.get_id(), err);
+        }
+    }
+
+    pub async fn add_remote_track(&mut self, track: &Arc<Track>) {
+        let (down_track, local_track) = track.subscribe(&self.get_id(), self.sender.clone());
+        match self.peer_connection.add_track(local_track.clone()).await {
             Ok(rtp_sender) => {
                 debug!("Successfully added track\n");
+                self.subscriptions.lock().await.insert(
+                    track.id().to_owned(),
+                    Subscription {
+                        track: track.clone(),
+                        down_track: down_track.clone(),
+                        rtp_sender: rtp_sender.clone(),
+                        subscribed: Instant::now(),
+                    },
+                );
+                send_message(
+                    &self.sender,
+                    &ServerMessage::TrackAdded {
+                        track_id: local_track.id().to_owned(),
+                        stream_id: local_track.stream_id().to_owned(),
+                        kind: local_track.kind().to_string(),
+                        layers: track.rids().to_vec(),
+                        muted: track.is_muted(),
+                    },
+                );
                 // Read incoming RTCP packets
                 // Before these packets are returned they are processed by interceptors. For things
                 // like NACK this needs to be called.
+                let bwe = self.bwe.clone();
+                let track = track.clone();
+                let dump = self.dump.clone();
                 tokio::spawn(async move {
-                    let mut rtcp_buf = vec![0u8; 1500];
-                    while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-                    debug!("End rtcp_buf {:?}\n", rtcp_buf);
-                    Result::<()>::Ok(())
+                    while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+                        if let Some(dump) = &dump {
+                            dump.rtcp(&packets);
+                        }
+                        bwe.lock().unwrap().on_rtcp(&packets);
+                        let mut lost = vec![];
+                        // The subscriber lost a picture and can't decode until the next keyframe.
+                        let mut picture_lost = false;
+                        for packet in &packets {
+                            let packet = packet.as_any();
+                            if let Some(nack) = packet.downcast_ref::<TransportLayerNack>() {
+                                for pair in &nack.nacks {
+                                    lost.extend(pair.packet_list());
+                                }
+                            }
+                            picture_lost |= packet.is::<PictureLossIndication>()
+                                || packet.is::<FullIntraRequest>();
+                        }
+                        if !lost.is_empty() {
+                            track.retransmit(&down_track, &lost).await;
+                        }
+                        if picture_lost {
+                            if let Some(rid) = down_track.current_layer() {
+                                track.request_keyframe(&rid).await;
+                            }
+                        }
+                    }
+                    debug!("End of rtcp for track {}", local_track.id());
                 });
             }
-            Err(err) => warn!("Unsuccessfully added track: {:?}\n", err),
+            Err(err) => {
+                track.unsubscribe(&self.get_id());
+                warn!("Unsuccessfully added track: {:?}\n", err);
+            }
+        }
+    }
+
+    /// Stops forwarding `track` to this peer, the removal is negotiated through
+    /// `on_negotiation_needed`.
+    pub async fn remove_remote_track(&self, track: &Track) {
+        let subscription = match self.subscriptions.lock().await.remove(track.id()) {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        track.unsubscribe(&self.get_id());
+        if let Err(err) = self
+            .peer_connection
+            .remove_track(&subscription.rtp_sender)
+            .await
+        {
+            warn!("Unsuccessfully removed track {}: {:?}", track.id(), err);
+            return;
+        }
+        debug!("Removed track {} from peer {}", track.id(), self.get_id());
+        send_message(
+            &self.sender,
+            &ServerMessage::TrackRemoved {
+                track_id: track.id().to_owned(),
+                stream_id: track.publisher_id().to_owned(),
+            },
+        );
+    }
+
+    /// Updates the bandwidth estimate and splits it between the tracks forwarded to this peer.
+    /// Tracks without simulcast take what they need, the simulcast ones share the rest evenly.
+    async fn allocate_layers(&self) {
+        let estimate = self.bwe.lock().unwrap().update();
+        let subscriptions = self.subscriptions.lock().await;
+        let mut fixed = 0;
+        let mut single = vec![];
+        let mut simulcast = vec![];
+        for subscription in subscriptions.values() {
+            if subscription.down_track.is_paused() || subscription.track.is_muted() {
+                subscription.down_track.set_budget(None);
+                continue;
+            }
+            let layers = subscription.track.layer_bitrates();
+            if subscription.track.is_simulcast() {
+                simulcast.push((subscription, layers));
+            } else {
+                let bitrate = layers.iter().map(|(_, bitrate)| bitrate).sum::<u64>();
+                fixed += bitrate;
+                single.push((subscription, bitrate));
+            }
+        }
+        let share = estimate
+            .bitrate
+            .map(|bitrate| bitrate.saturating_sub(fixed) / simulcast.len().max(1) as u64);
+        for (subscription, layers) in simulcast {
+            subscription.down_track.allocate(&layers, share);
+        }
+        // Without simulcast only the publisher can adapt, tracks are budgeted in proportion to
+        // what they are sending now and the publisher is told about it, see `send_bitrate_hint`.
+        for (subscription, bitrate) in single {
+            let budget = estimate
+                .bitrate
+                .filter(|_| fixed > 0)
+                .map(|estimate| (estimate as f64 * bitrate as f64 / fixed as f64) as u64);
+            subscription.down_track.set_budget(budget);
+        }
+    }
+
+    /// Caps the bitrate of this peer's tracks with a REMB, so that the subscriber with the least
+    /// bandwidth keeps up. Not sent while any subscribed track is simulcast or lacks feedback.
+    async fn send_bitrate_hint(&self) {
+        let tracks: Vec<_> = self.tracks.lock().await.values().cloned().collect();
+        let mut bitrate = 0;
+        let mut ssrcs = vec![];
+        for track in tracks
+            .iter()
+            .filter(|track| track.has_subscribers() && !track.is_muted())
+        {
+            match track.bitrate_hint() {
+                Some(hint) => bitrate += hint,
+                None => return,
+            }
+            ssrcs.extend(track.ssrcs());
+        }
+        if ssrcs.is_empty() {
+            return;
+        }
+        let remb = ReceiverEstimatedMaximumBitrate {
+            sender_ssrc: 0,
+            bitrate: bitrate.max(MIN_BITRATE) as f32,
+            ssrcs,
+        };
+        debug!(
+            "Hinting {} bps to publisher {}",
+            remb.bitrate,
+            self.get_id()
+        );
+        if let Err(err) = self.peer_connection.write_rtcp(&[Box::new(remb)]).await {
+            debug!("Error sending REMB to {}: {}", self.get_id(), err);
+        }
+    }
+
+    pub async fn stats(&self) -> PeerStats {
+        let estimate = self.bwe.lock().unwrap().estimate().clone();
+        let subscriptions = self
+            .subscriptions
+            .lock()
+            .await
+            .values()
+            .map(|subscription| {
+                subscription
+                    .down_track
+                    .stats(subscription.track.layer_bitrates())
+            })
+            .collect();
+        PeerStats {
+            id: self.get_id(),
+            estimate,
+            subscriptions,
+        }
+    }
+
+    /// Stops or resumes forwarding `track_id` to this peer.
+    pub async fn set_paused(
+        &self,
+        track_id: &str,
+        paused: bool,
+    ) -> std::result::Result<(), SignalError> {
+        let subscriptions = self.subscriptions.lock().await;
+        let subscription = subscriptions
+            .get(track_id)
+            .ok_or_else(|| not_forwarded(track_id))?;
+        debug!(
+            "Peer {} {} track {}",
+            self.get_id(),
+            if paused { "paused" } else { "resumed" },
+            track_id
+        );
+        subscription.down_track.set_paused(paused);
+        send_message(
+            &self.sender,
+            &ServerMessage::TrackPaused {
+                track_id: track_id.to_owned(),
+                paused,
+            },
+        );
+        Ok(())
+    }
+
+    /// Changes the video this peer receives at most.
+    pub async fn set_last_n(&self, last_n: LastN) {
+        debug!(
+            "Peer {} receives {:?} video tracks, pinned {:?}",
+            self.get_id(),
+            last_n.limit,
+            last_n.pinned
+        );
+        *self.last_n.lock().unwrap() = last_n;
+        self.apply_last_n().await;
+    }
+
+    /// Forwards the video of the first `limit` publishers and pauses the video of the others:
+    /// pinned publishers go first, then the latest dominant speakers of the group, then the
+    /// publishers subscribed to first. Tells the peer whenever the forwarded tracks change.
+    pub async fn apply_last_n(&self) {
+        let last_n = self.last_n.lock().unwrap().clone();
+        let recent = self.speakers.lock().unwrap().recent().to_vec();
+        let subscriptions = self.subscriptions.lock().await;
+        let mut video: Vec<&Subscription> = subscriptions
+            .values()
+            .filter(|subscription| subscription.track.kind() == RTPCodecType::Video)
+            .collect();
+        let rank = |order: &[String], stream_id: &str| {
+            order
+                .iter()
+                .position(|id| id == stream_id)
+                .unwrap_or(usize::MAX)
+        };
+        video.sort_by_key(|subscription| {
+            let stream_id = subscription.track.publisher_id();
+            (
+                rank(&last_n.pinned, stream_id),
+                rank(&recent, stream_id),
+                subscription.subscribed,
+            )
+        });
+        let limit = last_n.limit.unwrap_or(usize::MAX);
+        let mut changed = false;
+        for (position, subscription) in video.iter().enumerate() {
+            changed |= subscription
+                .down_track
+                .set_outside_last_n(position >= limit);
+        }
+        // Without a limit every track is forwarded, there is nothing to tell.
+        if !changed || last_n.limit.is_none() {
+            return;
         }
+        let track_ids: Vec<String> = video
+            .iter()
+            .take(limit)
+            .map(|subscription| subscription.track.id().to_owned())
+            .collect();
+        debug!("Peer {} receives video {:?}", self.get_id(), track_ids);
+        send_message(&self.sender, &ServerMessage::VideoForwarded { track_ids });
+    }
+
+    /// The tracks of `kind` this peer publishes.
+    pub async fn published_tracks(&self, kind: RTPCodecType) -> Vec<Arc<Track>> {
+        let tracks = self.tracks.lock().await;
+        tracks
+            .values()
+            .filter(|track| track.kind() == kind)
+            .cloned()
+            .collect()
+    }
+
+    /// Forwards layer `rid` of a simulcast track to this peer from its next keyframe on, or
+    /// lets the server pick the layer when `None`.
+    pub async fn select_layer(
+        &self,
+        track_id: &str,
+        rid: Option<String>,
+    ) -> std::result::Result<(), SignalError> {
+        let subscriptions = self.subscriptions.lock().await;
+        let subscription = subscriptions
+            .get(track_id)
+            .ok_or_else(|| not_forwarded(track_id))?;
+        if let Some(rid) = &rid {
+            if !subscription.track.rids().contains(rid) {
+                return Err(SignalError::new(
+                    ErrorCode::UnknownLayer,
+                    format!("track {} has no layer {}", track_id, rid),
+                ));
+            }
+        }
+        debug!(
+            "Peer {} selected layer {:?} of track {}",
+            self.get_id(),
+            rid,
+            track_id
+        );
+        subscription.down_track.request_layer(rid);
+        Ok(())
     }
 
     pub async fn renegotiate(&self) {
-        debug!("Renegotiation started for {}", self.get_id());
-        let offer = self.peer_connection.create_offer(None).await;
-        if offer.is_err() {
-            warn!(
-                "Error creating renegotiation offer {:?}",
-                offer.err().unwrap()
+        if self.publish_only {
+            debug!("Not renegotiating {}, it only publishes", self.get_id());
+            return;
+        }
+        let mut negotiation = self.negotiation.lock().await;
+        if self.peer_connection.signaling_state() != RTCSignalingState::Stable {
+            debug!(
+                "Renegotiation of {} deferred until signaling is stable",
+                self.get_id()
             );
+            negotiation.renegotiation_pending = true;
             return;
         }
+        negotiation.renegotiation_pending = false;
+        let ice_restart = std::mem::take(&mut negotiation.ice_restart_pending);
+        debug!(
+            "Renegotiation started for {}, ice restart: {}",
+            self.get_id(),
+            ice_restart
+        );
+        if let Err(err) = self.send_offer(ice_restart).await {
+            warn!("Error renegotiating {}: {:?}", self.get_id(), err);
+            let error = SignalError::new(ErrorCode::NegotiationFailed, err.to_string());
+            send_message(&self.sender, &error.to_message(None));
+        }
+    }
 
-        let offer = offer.unwrap();
-        let offer = RTCSessionDescriptionInit { sdp: offer };
-        match self
-            .peer_connection
-            .set_local_description(offer.sdp.clone())
-            .await
-        {
-            Ok(value) => {
-                debug!("Set local description {:?}", value);
-                let offer = serde_json::to_string(&offer).unwrap();
-                let msg = warp::ws::Message::text(offer);
-                match self.sender.send(Ok(msg)) {
-                    Err(err) => warn!("Error sending offer {:?}", err),
-                    _ => {}
-                }
+    /// Sends an offer with fresh ICE credentials, once signaling is stable.
+    pub async fn restart_ice(&self) {
+        // Only the publisher can restart ICE, by sending an offer itself.
+        if self.publish_only {
+            return;
+        }
+        info!("Restarting ICE for {}", self.get_id());
+        self.negotiation.lock().await.ice_restart_pending = true;
+        self.renegotiate().await;
+    }
+
+    async fn renegotiate_if_pending(&self) {
+        if self.negotiation.lock().await.renegotiation_pending {
+            self.renegotiate().await;
+        }
+    }
+
+    /// Restricts every transceiver to the codec pinned by the group for its kind, so that the
+    /// RTP forwarded between members can be decoded by all of them.
+    async fn apply_codec_preferences(&self) {
+        let codecs = self.group_codecs.lock().await.clone();
+        for transceiver in self.peer_connection.get_transceivers().await {
+            let codec = match codecs.get(transceiver.kind()) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            let preferences = vec![RTCRtpCodecParameters {
+                capability: codec.clone(),
+                ..Default::default()
+            }];
+            if let Err(err) = transceiver.set_codec_preferences(preferences).await {
+                warn!(
+                    "Unable to prefer {} on peer {}: {:?}",
+                    codec.mime_type,
+                    self.get_id(),
+                    err
+                );
             }
-            Err(err) => warn!("Set local description error {:?}", err),
+        }
+    }
+
+    async fn send_offer(&self, ice_restart: bool) -> Result<()> {
+        self.apply_codec_preferences().await;
+        let options = RTCOfferOptions {
+            ice_restart,
+            ..Default::default()
         };
+        let offer = self.peer_connection.create_offer(Some(options)).await?;
+        self.peer_connection
+            .set_local_description(offer.clone())
+            .await?;
+        debug!("Set local description {:?}", offer);
+        send_message(&self.sender, &ServerMessage::Offer { sdp: offer.sdp });
+        Ok(())
+    }
+
+    /// Whether this peer should be sent `track`, it never receives its own tracks.
+    pub fn wants_track(&self, track: &Track) -> bool {
+        !self.publish_only
+            && track.publisher_id() != self.get_id()
+            && self.role.can_receive(track.role(), track.kind())
     }
 
     pub fn get_tracks(&self) -> &Arc<Mutex<HashMap<String, Arc<Track>>>> {
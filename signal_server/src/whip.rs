@@ -0,0 +1,719 @@
+//! WHIP (WebRTC-HTTP Ingestion Protocol, RFC 9725) endpoint for publishers which don't speak the
+//! websocket protocol, e.g. hardware encoders and OBS.
+//!
+//! `POST /whip/<group>` with an SDP offer joins the group and publishes into it like a websocket
+//! client would, the answer carries the location of the session. Candidates are trickled with
+//! `PATCH` on that location, `DELETE` ends the session. When authentication is enabled, every
+//! request carries the client token as `Authorization: Bearer <token>`.
+//!
+//! WHIP publishers receive nothing, and since the server can't send them offers it never restarts
+//! ICE itself. Publishers restart it with a `PATCH` carrying new ICE credentials, answered with
+//! the server's new credentials and candidates. The ICE session is identified by an entity tag,
+//! the server's username fragment, which trickled candidates may be matched against.
+use crate::auth::Claims;
+use crate::protocol::{ErrorCode, SignalError};
+use crate::role::Role;
+use crate::webrtc::{LastN, WebRTCConnection};
+use crate::{ws, Client, Groups, Result, ServerOptions, WhipSessions};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+use warp::http::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, LINK, LOCATION,
+};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::Reply;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+/// Largest offer or candidate fragment accepted.
+pub const MAX_BODY_SIZE: u64 = 64 * 1024;
+/// How long the server gathers its candidates before answering, the answer carries them all.
+const GATHERING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often sessions are checked for a failed peer connection.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+const SDP: &str = "application/sdp";
+const TRICKLE_ICE_SDPFRAG: &str = "application/trickle-ice-sdpfrag";
+
+#[derive(Debug, Deserialize)]
+pub struct WhipQuery {
+    /// Role to publish as, resolved as for `join`. Required unless the token has one, as the
+    /// default role can't publish.
+    role: Option<Role>,
+}
+
+/// `POST /whip/<group>`: publishes the offered media into `group`.
+pub async fn publish_handler(
+    group_id: String,
+    query: WhipQuery,
+    headers: HeaderMap,
+    body: Bytes,
+    sessions: WhipSessions,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    if !is_content_type(&headers, SDP) {
+        return Ok(unsupported_media_type(SDP));
+    }
+    let offer = match String::from_utf8(body.to_vec()) {
+        Ok(offer) => offer,
+        Err(_) => {
+            return Ok(
+                warp::reply::with_status("offer is not UTF-8", StatusCode::BAD_REQUEST)
+                    .into_response(),
+            )
+        }
+    };
+    let (session_id, answer) = match publish(
+        &group_id,
+        query.role,
+        authorization(&headers),
+        offer,
+        &sessions,
+        &groups,
+        &options,
+    )
+    .await
+    {
+        Ok(published) => published,
+        Err(err) => {
+            warn!("Rejecting WHIP offer for group {}: {}", group_id, err);
+            return Ok(error_response(&err));
+        }
+    };
+
+    let etag = entity_tag(&answer);
+    let mut response = warp::reply::with_status(answer, StatusCode::CREATED).into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(SDP));
+    if let Some(etag) = etag {
+        headers.insert(ETAG, etag);
+    }
+    if let Ok(location) = HeaderValue::from_str(&format!("/whip/{}/{}", group_id, session_id)) {
+        headers.insert(LOCATION, location);
+    }
+    // The ICE servers websocket clients get in `config`, for publishers to create their peer
+    // connection with before the next session.
+    for server in options.client_ice_servers() {
+        for url in &server.urls {
+            let mut link = format!("<{}>; rel=\"ice-server\"", url);
+            if let (Some(username), Some(credential)) = (&server.username, &server.credential) {
+                link.push_str(&format!(
+                    "; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                    username, credential
+                ));
+            }
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                headers.append(LINK, link);
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Joins `group_id` as a new client and answers its offer. Returns the id of the session and
+/// the answer.
+async fn publish(
+    group_id: &str,
+    role: Option<Role>,
+    authorization: Option<&str>,
+    offer: String,
+    sessions: &WhipSessions,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> std::result::Result<(String, String), SignalError> {
+    let (claims, role) = match &options.authenticator {
+        Some(authenticator) => {
+            let token = bearer_token(authorization)?;
+            let claims = authenticator.verify(token).map_err(|err| {
+                SignalError::new(ErrorCode::Unauthorized, format!("invalid token: {}", err))
+            })?;
+            let claims = Some(claims);
+            let role = ws::authorize_join(&claims, group_id, role)?;
+            (claims, role)
+        }
+        None => (None, role.unwrap_or_default()),
+    };
+    if !role.can_publish() {
+        return Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "{:?} clients may not publish, pick a role with ?role=",
+                role
+            ),
+        ));
+    }
+    ws::check_capacity(group_id, groups, options).await?;
+
+    // Messages for the client have nowhere to go, WHIP has no channel from the server.
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let session_id = Uuid::new_v4().as_simple().to_string();
+    let log_id = session_id.clone();
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            debug!("Dropping message for WHIP client {}: {:?}", log_id, message);
+        }
+    });
+    let client = Arc::new(Mutex::new(Client {
+        client_id: session_id.clone(),
+        peer_connection: None,
+        sender,
+        group: Some(group_id.to_owned()),
+        role,
+        protocol_version: None,
+        claims,
+        pending_candidates: vec![],
+        last_n: LastN::default(),
+        resume_token: Uuid::new_v4().as_simple().to_string(),
+        epoch: 0,
+        outbound: None,
+    }));
+    let group = ws::group_entry(groups, group_id).await;
+    group.lock().await.subscribe(client.clone()).await;
+    let answer = match negotiate(&client, &group, offer, options).await {
+        Ok(answer) => answer,
+        Err(err) => {
+            ws::leave(&session_id, &client, groups, options).await;
+            return Err(err);
+        }
+    };
+    sessions
+        .lock()
+        .await
+        .insert(session_id.clone(), client.clone());
+    spawn_watchdog(
+        session_id.clone(),
+        client,
+        sessions.clone(),
+        groups.clone(),
+        options.clone(),
+    );
+    info!(
+        "WHIP client {} publishes into group {} as {:?}",
+        session_id, group_id, role
+    );
+    Ok((session_id, answer))
+}
+
+/// Creates the peer connection of `client` and answers `offer` with all the server candidates.
+async fn negotiate(
+    client: &Arc<Mutex<Client>>,
+    group: &Arc<Mutex<crate::Group>>,
+    offer: String,
+    options: &ServerOptions,
+) -> std::result::Result<String, SignalError> {
+    ws::check_offer_codecs(group, &offer, &options.codecs).await?;
+    let (sender, role, participant) = {
+        let client = client.lock().await;
+        (
+            client.sender.clone(),
+            client.role,
+            client.identity().to_owned(),
+        )
+    };
+    let mut peer_connection = WebRTCConnection::new(
+        sender,
+        group.clone(),
+        role,
+        &options.api,
+        options.ice_servers.clone(),
+        LastN::default(),
+        options.rtp_dump_directory.as_deref(),
+    )
+    .await
+    .map_err(|err| SignalError::new(ErrorCode::PeerConnectionFailed, err.to_string()))?;
+    peer_connection.set_publish_only();
+    peer_connection.set_participant(&participant);
+    peer_connection.setup_callbacks().await;
+    let mut gathered = peer_connection
+        .peer_connection
+        .gathering_complete_promise()
+        .await;
+    if let Err(err) = peer_connection.process_offer(offer).await {
+        peer_connection.close().await;
+        return Err(SignalError::new(
+            ErrorCode::NegotiationFailed,
+            err.to_string(),
+        ));
+    }
+    if tokio::time::timeout(GATHERING_TIMEOUT, gathered.recv())
+        .await
+        .is_err()
+    {
+        debug!(
+            "Answering {} with the candidates gathered so far",
+            peer_connection.get_id()
+        );
+    }
+    let answer = match peer_connection.peer_connection.local_description().await {
+        Some(answer) => answer.sdp,
+        None => {
+            peer_connection.close().await;
+            return Err(SignalError::new(
+                ErrorCode::NegotiationFailed,
+                "no local description after answering",
+            ));
+        }
+    };
+    client.lock().await.peer_connection = Some(peer_connection);
+    Ok(answer)
+}
+
+/// Ends the session once its peer connection failed or was closed, publishers going away don't
+/// always delete their session.
+fn spawn_watchdog(
+    session_id: String,
+    client: Arc<Mutex<Client>>,
+    sessions: WhipSessions,
+    groups: Groups,
+    options: ServerOptions,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(WATCHDOG_INTERVAL).await;
+            let state = match &client.lock().await.peer_connection {
+                Some(pc) => pc.peer_connection.connection_state(),
+                // Deleted meanwhile.
+                None => break,
+            };
+            if state == RTCPeerConnectionState::Failed || state == RTCPeerConnectionState::Closed {
+                if sessions.lock().await.remove(&session_id).is_some() {
+                    info!("WHIP client {} went away", session_id);
+                    ws::leave(&session_id, &client, &groups, &options).await;
+                }
+                break;
+            }
+        }
+    });
+}
+
+/// `PATCH /whip/<group>/<session>`: adds the candidates trickled by the publisher, or restarts ICE
+/// when the fragment carries new ICE credentials.
+pub async fn trickle_handler(
+    group_id: String,
+    session_id: String,
+    headers: HeaderMap,
+    body: Bytes,
+    sessions: WhipSessions,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    let client = match find_session(&sessions, &group_id, &session_id).await {
+        Some(client) => client,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let client = client.lock().await;
+    if let Err(err) = authorize_session(authorization(&headers), &client.claims, &options) {
+        return Ok(error_response(&err));
+    }
+    if !is_content_type(&headers, TRICKLE_ICE_SDPFRAG) {
+        return Ok(unsupported_media_type(TRICKLE_ICE_SDPFRAG));
+    }
+    let pc = match &client.peer_connection {
+        Some(pc) => pc,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let fragment = String::from_utf8_lossy(&body);
+    let (ufrag, candidates) = parse_sdpfrag(&fragment);
+    let remote = match pc.peer_connection.remote_description().await {
+        Some(remote) => remote.sdp,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let local = pc.peer_connection.local_description().await;
+    let etag = local.and_then(|local| entity_tag(&local.sdp));
+    let if_match = headers.get(IF_MATCH);
+    if if_match.is_some_and(|tag| tag != "*" && Some(tag) != etag.as_ref()) {
+        return Ok(warp::reply::with_status(
+            "the ICE session was restarted meanwhile",
+            StatusCode::PRECONDITION_FAILED,
+        )
+        .into_response());
+    }
+    let restart = match (ufrag, ice_ufrag(&remote)) {
+        (Some(ufrag), Some(current)) => ufrag != current,
+        _ => false,
+    };
+    if restart {
+        let (ufrag, pwd) = match (ufrag, ice_pwd(&fragment)) {
+            (Some(ufrag), Some(pwd)) => (ufrag, pwd),
+            _ => {
+                return Ok(warp::reply::with_status(
+                    "an ICE restart needs ice-ufrag and ice-pwd",
+                    StatusCode::BAD_REQUEST,
+                )
+                .into_response())
+            }
+        };
+        debug!("WHIP client {} restarts ICE", session_id);
+        match pc.process_offer(restart_offer(&remote, ufrag, pwd)).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(StatusCode::CONFLICT.into_response()),
+            Err(err) => {
+                return Ok(warp::reply::with_status(
+                    format!("unable to restart ICE: {}", err),
+                    StatusCode::BAD_REQUEST,
+                )
+                .into_response())
+            }
+        }
+        for candidate in candidates {
+            if let Err(err) = pc.process_ice_candidate(candidate).await {
+                debug!("Dropping candidate of WHIP client {}: {}", session_id, err);
+            }
+        }
+        // The restart started gathering anew, waited for without the client locked so that the
+        // answer carries all the new candidates.
+        let rtc = pc.peer_connection.clone();
+        let mut gathered = rtc.gathering_complete_promise().await;
+        drop(client);
+        if tokio::time::timeout(GATHERING_TIMEOUT, gathered.recv())
+            .await
+            .is_err()
+        {
+            debug!("Answering the ICE restart of {} early", session_id);
+        }
+        let local = match rtc.local_description().await {
+            Some(local) => local.sdp,
+            None => return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+        };
+        let etag = entity_tag(&local);
+        let mut response = ice_sdpfrag(&local).into_response();
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(TRICKLE_ICE_SDPFRAG));
+        if let Some(etag) = etag {
+            headers.insert(ETAG, etag);
+        }
+        return Ok(response);
+    }
+    for candidate in candidates {
+        if let Err(err) = pc.process_ice_candidate(candidate).await {
+            return Ok(warp::reply::with_status(
+                format!("invalid candidate: {}", err),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `DELETE /whip/<group>/<session>`: ends the session and withdraws its tracks.
+pub async fn delete_handler(
+    group_id: String,
+    session_id: String,
+    authorization: Option<String>,
+    sessions: WhipSessions,
+    groups: Groups,
+    options: ServerOptions,
+) -> Result<impl Reply> {
+    let client = match find_session(&sessions, &group_id, &session_id).await {
+        Some(client) => client,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let claims = client.lock().await.claims.clone();
+    if let Err(err) = authorize_session(authorization.as_deref(), &claims, &options) {
+        return Ok(error_response(&err));
+    }
+    if sessions.lock().await.remove(&session_id).is_some() {
+        info!("WHIP client {} ended its session", session_id);
+        ws::leave(&session_id, &client, &groups, &options).await;
+    }
+    Ok(StatusCode::OK.into_response())
+}
+
+async fn find_session(
+    sessions: &WhipSessions,
+    group_id: &str,
+    session_id: &str,
+) -> Option<Arc<Mutex<Client>>> {
+    let client = sessions.lock().await.get(session_id).cloned()?;
+    let in_group = client.lock().await.group.as_deref() == Some(group_id);
+    in_group.then_some(client)
+}
+
+/// Checks the token presented for a session belongs to the client which started it.
+fn authorize_session(
+    authorization: Option<&str>,
+    claims: &Option<Claims>,
+    options: &ServerOptions,
+) -> std::result::Result<(), SignalError> {
+    let authenticator = match &options.authenticator {
+        Some(authenticator) => authenticator,
+        None => return Ok(()),
+    };
+    let presented = authenticator
+        .verify(bearer_token(authorization)?)
+        .map_err(|err| {
+            SignalError::new(ErrorCode::Unauthorized, format!("invalid token: {}", err))
+        })?;
+    match claims {
+        Some(claims) if claims.sub == presented.sub => Ok(()),
+        _ => Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("the session doesn't belong to {}", presented.sub),
+        )),
+    }
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn bearer_token(authorization: Option<&str>) -> std::result::Result<&str, SignalError> {
+    authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| SignalError::new(ErrorCode::Unauthorized, "a bearer token is required"))
+}
+
+fn error_response(err: &SignalError) -> warp::reply::Response {
+    let status = match err.code {
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::GroupFull => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::PeerConnectionFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    warp::reply::with_status(err.message.clone(), status).into_response()
+}
+
+fn unsupported_media_type(expected: &str) -> warp::reply::Response {
+    warp::reply::with_status(
+        format!("expected {}", expected),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+    )
+    .into_response()
+}
+
+/// Whether the body of a request is of media type `expected`, whatever the parameters.
+fn is_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(expected))
+}
+
+/// The ICE username fragment of an SDP or SDP fragment.
+fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim_end().strip_prefix("a=ice-ufrag:"))
+}
+
+/// The ICE password of an SDP or SDP fragment.
+fn ice_pwd(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim_end().strip_prefix("a=ice-pwd:"))
+}
+
+/// The entity tag of the ICE session of the server, described by its local description `sdp`:
+/// its username fragment, which changes with every restart.
+fn entity_tag(sdp: &str) -> Option<HeaderValue> {
+    let ufrag = ice_ufrag(sdp)?;
+    HeaderValue::from_str(&format!("\"{}\"", ufrag)).ok()
+}
+
+/// `offer` restarting ICE with the credentials `ufrag` and `pwd`, without the candidates of the
+/// previous ICE session.
+fn restart_offer(offer: &str, ufrag: &str, pwd: &str) -> String {
+    offer
+        .lines()
+        .map(str::trim_end)
+        .filter_map(|line| {
+            if line.starts_with("a=ice-ufrag:") {
+                Some(format!("a=ice-ufrag:{}", ufrag))
+            } else if line.starts_with("a=ice-pwd:") {
+                Some(format!("a=ice-pwd:{}", pwd))
+            } else if line.starts_with("a=candidate:") || line == "a=end-of-candidates" {
+                None
+            } else {
+                Some(line.to_owned())
+            }
+        })
+        .map(|line| line + "\r\n")
+        .collect()
+}
+
+/// The ICE credentials and candidates of `sdp` as an SDP fragment (RFC 8840), with the media
+/// sections they belong to.
+fn ice_sdpfrag(sdp: &str) -> String {
+    const ICE_LINES: [&str; 6] = [
+        "m=",
+        "a=mid:",
+        "a=ice-ufrag:",
+        "a=ice-pwd:",
+        "a=ice-options:",
+        "a=candidate:",
+    ];
+    sdp.lines()
+        .map(str::trim_end)
+        .filter(|line| {
+            ICE_LINES.iter().any(|prefix| line.starts_with(prefix))
+                || *line == "a=end-of-candidates"
+        })
+        .map(|line| line.to_owned() + "\r\n")
+        .collect()
+}
+
+/// The username fragment and candidates of a trickle ICE SDP fragment (RFC 8840).
+fn parse_sdpfrag(fragment: &str) -> (Option<&str>, Vec<RTCIceCandidateInit>) {
+    let ufrag = ice_ufrag(fragment);
+    let mut candidates = vec![];
+    let mut mid = None;
+    let mut mline_index = None;
+    for line in fragment.lines().map(str::trim_end) {
+        if line.starts_with("m=") {
+            mid = None;
+            mline_index = Some(mline_index.map_or(0, |index| index + 1));
+        } else if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_owned());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            if candidate.starts_with("candidate:") {
+                candidates.push(RTCIceCandidateInit {
+                    candidate: candidate.to_owned(),
+                    sdp_mid: mid.clone(),
+                    sdp_mline_index: mline_index,
+                    username_fragment: ufrag.map(str::to_owned),
+                });
+            }
+        }
+    }
+    (ufrag, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sdp_fragments() {
+        let fragment = [
+            "a=ice-ufrag:EsAw",
+            "a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1",
+            "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+            "a=mid:0",
+            "a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host generation 0",
+            "a=candidate:3471623853 1 udp 2122194687 198.51.100.2 61765 typ host generation 0",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            "a=mid:1",
+            "a=candidate:473322822 1 tcp 1518280447 192.0.2.1 9 typ host tcptype active",
+            "a=end-of-candidates",
+            "",
+        ]
+        .join("\r\n");
+        let (ufrag, candidates) = parse_sdpfrag(&fragment);
+        assert_eq!(ufrag, Some("EsAw"));
+        let parsed: Vec<_> = candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    candidate.candidate.split(' ').next().unwrap(),
+                    candidate.sdp_mid.as_deref(),
+                    candidate.sdp_mline_index,
+                    candidate.username_fragment.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("candidate:1387637174", Some("0"), Some(0), Some("EsAw")),
+                ("candidate:3471623853", Some("0"), Some(0), Some("EsAw")),
+                ("candidate:473322822", Some("1"), Some(1), Some("EsAw")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_fragments_without_media_or_ufrag() {
+        let (ufrag, candidates) =
+            parse_sdpfrag("a=candidate:1 1 udp 2122260223 192.0.2.1 61764 typ host\n");
+        assert_eq!(ufrag, None);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].sdp_mid, None);
+        assert_eq!(candidates[0].sdp_mline_index, None);
+        assert_eq!(candidates[0].username_fragment, None);
+        assert!(parse_sdpfrag("a=end-of-candidates\r\n").1.is_empty());
+        assert!(parse_sdpfrag("").1.is_empty());
+    }
+
+    #[test]
+    fn restarts_ice_in_offers() {
+        let offer = [
+            "v=0",
+            "o=- 1 1 IN IP4 0.0.0.0",
+            "a=ice-options:trickle",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            "a=mid:0",
+            "a=ice-ufrag:EsAw",
+            "a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1",
+            "a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host",
+            "a=end-of-candidates",
+            "a=sendonly",
+            "",
+        ]
+        .join("\r\n");
+        let restarted = restart_offer(&offer, "Zq7v", "mUgg2Gfw2OCVyyKyl6RT1qPE");
+        assert_eq!(
+            restarted,
+            [
+                "v=0",
+                "o=- 1 1 IN IP4 0.0.0.0",
+                "a=ice-options:trickle",
+                "m=video 9 UDP/TLS/RTP/SAVPF 96",
+                "a=mid:0",
+                "a=ice-ufrag:Zq7v",
+                "a=ice-pwd:mUgg2Gfw2OCVyyKyl6RT1qPE",
+                "a=sendonly",
+                "",
+            ]
+            .join("\r\n")
+        );
+        assert_eq!(ice_ufrag(&restarted), Some("Zq7v"));
+        assert_eq!(ice_pwd(&restarted), Some("mUgg2Gfw2OCVyyKyl6RT1qPE"));
+    }
+
+    #[test]
+    fn answers_restarts_with_sdp_fragments() {
+        let answer = [
+            "v=0",
+            "o=- 2 2 IN IP4 0.0.0.0",
+            "a=group:BUNDLE 0",
+            "m=video 9 UDP/TLS/RTP/SAVPF 96",
+            "c=IN IP4 0.0.0.0",
+            "a=setup:active",
+            "a=mid:0",
+            "a=ice-ufrag:Zq7v",
+            "a=ice-pwd:mUgg2Gfw2OCVyyKyl6RT1qPE",
+            "a=rtpmap:96 VP8/90000",
+            "a=candidate:1 1 udp 2130706431 192.0.2.1 50000 typ host",
+            "a=end-of-candidates",
+            "a=recvonly",
+            "",
+        ]
+        .join("\r\n");
+        let fragment = ice_sdpfrag(&answer);
+        assert_eq!(
+            fragment,
+            [
+                "m=video 9 UDP/TLS/RTP/SAVPF 96",
+                "a=mid:0",
+                "a=ice-ufrag:Zq7v",
+                "a=ice-pwd:mUgg2Gfw2OCVyyKyl6RT1qPE",
+                "a=candidate:1 1 udp 2130706431 192.0.2.1 50000 typ host",
+                "a=end-of-candidates",
+                "",
+            ]
+            .join("\r\n")
+        );
+        let (ufrag, candidates) = parse_sdpfrag(&fragment);
+        assert_eq!(ufrag, Some("Zq7v"));
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(entity_tag(&answer).unwrap(), "\"Zq7v\"");
+    }
+}
@@ -1,41 +1,115 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::webrtc::WebRTCConnection;
-use crate::{Client, Clients, Group, Groups};
-use futures::{FutureExt, StreamExt};
+use crate::auth::{is_token, Claims};
+use crate::codecs;
+use crate::protocol::{
+    encode_message, negotiate_version, send_message, ClientEnvelope, ClientMessage, ClientReceiver,
+    ErrorCode, GroupLimits, ServerMessage, SignalError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::role::Role;
+use crate::speaker;
+use crate::webrtc::{published_kinds, LastN, WebRTCConnection, MAX_PENDING_CANDIDATES};
+use crate::{Client, Clients, Group, Groups, ServerOptions};
+use futures::stream::SplitSink;
+use futures::{SinkExt, StreamExt};
 use serde_json::Value;
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::{debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
 
-pub async fn client_connection(ws: WebSocket, clients: Clients, groups: Groups) {
-    let (client_ws_sender, mut client_ws_rcv) = ws.split();
-    let (client_sender, client_rcv) = mpsc::unbounded_channel();
+/// Where the messages queued for a client go.
+///
+/// The queue outlives the websocket: while a client is detached its messages are kept until it
+/// resumes the session or the resume grace period runs out.
+#[derive(Debug)]
+pub enum Outbound {
+    /// Forwarded to a websocket until `stop` fires, the task then hands the queue back.
+    Attached {
+        stop: oneshot::Sender<()>,
+        forward: JoinHandle<ClientReceiver>,
+    },
+    Detached(ClientReceiver),
+}
 
-    let client_rcv = UnboundedReceiverStream::new(client_rcv);
+pub async fn client_connection(
+    ws: WebSocket,
+    clients: Clients,
+    groups: Groups,
+    options: ServerOptions,
+    claims: Option<Claims>,
+    protocol_version: u32,
+    resume: Option<String>,
+) {
+    let (client_ws_sender, mut client_ws_rcv) = ws.split();
 
-    // Forward messages fro mthe unbounded_channelto the websocket connection
-    tokio::task::spawn(client_rcv.forward(client_ws_sender).map(|result| {
-        if let Err(e) = result {
-            warn!("error sending websocket msg: {}", e);
+    let resumed = match resume {
+        Some(token) => resume_session(&clients, &token, &options).await,
+        None => None,
+    };
+    let is_resumed = resumed.is_some();
+    let (uuid, client, client_rcv, epoch) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let (client_sender, client_rcv) = mpsc::unbounded_channel();
+            let uuid = Uuid::new_v4().as_simple().to_string();
+            let new_client = Client {
+                client_id: uuid.clone(),
+                peer_connection: None,
+                sender: client_sender,
+                group: None,
+                role: Role::default(),
+                protocol_version: None,
+                claims,
+                pending_candidates: vec![],
+                last_n: LastN {
+                    limit: options.last_n,
+                    pinned: vec![],
+                },
+                resume_token: Uuid::new_v4().as_simple().to_string(),
+                epoch: 0,
+                outbound: None,
+            };
+            options
+                .resume_tokens
+                .lock()
+                .unwrap()
+                .insert(new_client.resume_token.clone(), uuid.clone());
+            let client = Arc::new(Mutex::new(new_client));
+            clients.lock().await.insert(uuid.clone(), client.clone());
+            (uuid, client, client_rcv, 0)
         }
-    }));
-
-    let uuid = Uuid::new_v4().as_simple().to_string();
-    let client_sender = Arc::new(Box::new(client_sender));
-    let new_client = Client {
-        client_id: uuid.clone(),
-        peer_connection: None,
-        sender: client_sender,
-        group: None,
     };
 
-    clients
-        .lock()
-        .await
-        .insert(uuid.clone(), Arc::new(Mutex::new(new_client)));
+    {
+        let mut client = client.lock().await;
+        let config = ServerMessage::Config {
+            protocol_version: client.protocol_version.unwrap_or(protocol_version),
+            client_id: uuid.clone(),
+            ice_servers: options.client_ice_servers(),
+            limits: GroupLimits {
+                max_clients: options.max_group_clients,
+            },
+            resume_token: client.resume_token.clone(),
+            resumed: is_resumed,
+        };
+        let (stop, stopped) = oneshot::channel();
+        // config goes out ahead of anything queued while the client was detached
+        let forward = tokio::spawn(forward_messages(
+            encode_message(&config),
+            client_rcv,
+            client_ws_sender,
+            stopped,
+        ));
+        client.outbound = Some(Outbound::Attached { stop, forward });
+    }
+    if is_resumed {
+        info!("{} resumed its session", uuid);
+    }
 
     while let Some(result) = client_ws_rcv.next().await {
         let msg = match result {
@@ -45,125 +119,842 @@ pub async fn client_connection(ws: WebSocket, clients: Clients, groups: Groups)
                 break;
             }
         };
-        client_msg(&uuid, msg, &clients, &groups).await;
+        client_msg(&uuid, msg, &clients, &groups, &options).await;
     }
 
-    clients.lock().await.remove(&uuid);
+    detach(&uuid, &client, epoch, &clients, &groups, &options).await;
+}
 
-    info!("{} disconnected", uuid);
+/// Forwards queued messages to the websocket until it fails or `stopped` fires, returns the
+/// queue so another websocket can take over.
+async fn forward_messages(
+    first: Option<Message>,
+    mut client_rcv: ClientReceiver,
+    mut client_ws_sender: SplitSink<WebSocket, Message>,
+    mut stopped: oneshot::Receiver<()>,
+) -> ClientReceiver {
+    if let Some(message) = first {
+        if let Err(e) = client_ws_sender.send(message).await {
+            warn!("error sending websocket msg: {}", e);
+            return client_rcv;
+        }
+    }
+    loop {
+        let message = tokio::select! {
+            _ = &mut stopped => break,
+            message = client_rcv.recv() => match message {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    warn!("error queued for websocket: {}", e);
+                    continue;
+                }
+                None => break,
+            },
+        };
+        if let Err(e) = client_ws_sender.send(message).await {
+            warn!("error sending websocket msg: {}", e);
+            break;
+        }
+    }
+    let _ = client_ws_sender.close().await;
+    client_rcv
 }
 
-// msg structure
-// {
-//  stream-group: ...,
-//  ...
-// }
-struct Headers {
-    stream_group: Option<String>,
+/// Takes over the session of `token`, stopping the websocket still attached to it, if any.
+/// Returns the client along with its message queue and the epoch of the new attachment.
+async fn resume_session(
+    clients: &Clients,
+    token: &str,
+    options: &ServerOptions,
+) -> Option<(String, Arc<Mutex<Client>>, ClientReceiver, u64)> {
+    let client_id = options.resume_tokens.lock().unwrap().get(token).cloned()?;
+    let client = clients.lock().await.get(&client_id).cloned()?;
+    let (outbound, epoch) = {
+        let mut guard = client.lock().await;
+        if !is_token(token, &guard.resume_token) {
+            return None;
+        }
+        // Sessions that expired meanwhile have no queue left to take over.
+        let outbound = guard.outbound.take()?;
+        guard.epoch += 1;
+        (outbound, guard.epoch)
+    };
+    let client_rcv = match outbound {
+        Outbound::Attached { stop, forward } => {
+            let _ = stop.send(());
+            match forward.await {
+                Ok(client_rcv) => client_rcv,
+                Err(err) => {
+                    warn!("Unable to take over the message queue: {}", err);
+                    return None;
+                }
+            }
+        }
+        Outbound::Detached(client_rcv) => client_rcv,
+    };
+    Some((client_id, client, client_rcv, epoch))
 }
 
-async fn parse_headers(msg: &Value, groups: &Groups) -> Headers {
-    let mut group_id: Option<String> = None;
-    if let Some(headers) = msg.get("headers") {
-        if let Some(group) = headers.get("stream-group") {
-            let id = group.as_str().unwrap().to_string();
-            let mut groups = groups.lock().await;
-            if !groups.contains_key(&id) {
-                let group = Arc::new(Mutex::new(Group::new()));
-                groups.insert(id.clone(), group);
+/// Detaches the websocket of attachment `epoch` from the client. The session is kept for the
+/// resume grace period, or ended right away when resumption is disabled.
+async fn detach(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    epoch: u64,
+    clients: &Clients,
+    groups: &Groups,
+    options: &ServerOptions,
+) {
+    let outbound = {
+        let mut client = client.lock().await;
+        if client.epoch != epoch {
+            info!("{} disconnected, its session was resumed", client_id);
+            return;
+        }
+        client.outbound.take()
+    };
+    let client_rcv = match outbound {
+        Some(Outbound::Attached { stop, forward }) => {
+            let _ = stop.send(());
+            forward.await.ok()
+        }
+        Some(Outbound::Detached(client_rcv)) => Some(client_rcv),
+        None => None,
+    };
+
+    let grace = options.resume_grace_period;
+    match client_rcv {
+        Some(client_rcv) if !grace.is_zero() => {
+            client.lock().await.outbound = Some(Outbound::Detached(client_rcv));
+            info!(
+                "{} disconnected, session kept for {:?} to be resumed",
+                client_id, grace
+            );
+            let client_id = client_id.to_owned();
+            let client = client.clone();
+            let clients = clients.clone();
+            let groups = groups.clone();
+            let options = options.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                expire_session(&client_id, &client, epoch, &clients, &groups, &options).await;
+            });
+        }
+        _ => {
+            if let Some(client) = clients.lock().await.remove(client_id) {
+                forget_resume_token(&client, options).await;
+                leave(client_id, &client, groups, options).await;
             }
-            group_id = Some(id);
+            info!("{} disconnected", client_id);
         }
     }
-    Headers {
-        stream_group: group_id,
+}
+
+/// Ends a detached session unless it was resumed since attachment `epoch`.
+async fn expire_session(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    epoch: u64,
+    clients: &Clients,
+    groups: &Groups,
+    options: &ServerOptions,
+) {
+    {
+        let mut guard = client.lock().await;
+        if guard.epoch != epoch || !matches!(guard.outbound, Some(Outbound::Detached(_))) {
+            return;
+        }
+        // Dropping the queue keeps resumes racing with the expiry from taking the session over.
+        guard.outbound = None;
     }
+    clients.lock().await.remove(client_id);
+    forget_resume_token(client, options).await;
+    leave(client_id, client, groups, options).await;
+    info!("{} did not resume its session in time", client_id);
 }
 
-async fn client_msg(client_id: &str, msg: Message, clients: &Clients, groups: &Groups) {
+/// Drops the resume token of a client whose session ended.
+async fn forget_resume_token(client: &Arc<Mutex<Client>>, options: &ServerOptions) {
+    let token = client.lock().await.resume_token.clone();
+    options.resume_tokens.lock().unwrap().remove(&token);
+}
+
+async fn client_msg(
+    client_id: &str,
+    msg: Message,
+    clients: &Clients,
+    groups: &Groups,
+    options: &ServerOptions,
+) {
     info!("received message from {}", client_id);
     debug!("msg is:\n{:?}", msg);
+    let client = match clients.lock().await.get(client_id) {
+        Some(client) => client.clone(),
+        None => return,
+    };
+
     let message_str = match msg.to_str() {
         Ok(v) => v,
-        Err(_) => return,
-    };
-    let message: Value = serde_json::from_str(message_str).unwrap();
-
-    let headers = parse_headers(&message, groups).await;
-
-    // FIXME: client.lock() implicitly prevents other messages from being processed concurrently.
-    let clients = clients.lock().await;
-    let client = clients.get(client_id);
-    let client = client.unwrap();
-
-    if let Some(sdp_message) = message.get("sdp") {
-        match sdp_message.get("type") {
-            Some(sdp_message_type) => {
-                // create peer_connection
-                match sdp_message_type.as_str().unwrap() {
-                    "offer" => {
-                        info!("Got offer from client {}", client_id);
-                        let group_id = match headers.stream_group {
-                            Some(v) => v,
-                            None => {
-                                panic!("It is Forbidden to process client without a group assigned")
-                            }
-                        };
-                        let group = groups.lock().await;
-                        let group = group.get(&group_id).unwrap().clone();
-                        // FIXME: cloning the mutex with client is potentially dangerous ad &Clients and
-                        // Groups[group] contains the client.
-                        group.lock().await.subscribe(client.clone()).await;
-                        debug!("State of group after subscribe {:?}", group);
-                        let mut peer_connection = match WebRTCConnection::new(
-                            client.lock().await.sender.clone(),
-                            group.clone(),
-                        )
-                        .await
-                        {
-                            Ok(conn) => {
-                                debug!("Successfull WebRTCConnection created {:?}", conn);
-                                conn
-                            }
-                            Err(err) => {
-                                panic!("{:?}", err);
-                            }
-                        };
-                        peer_connection.setup_callbacks().await;
-                        group.lock().await.add_tracks(&mut peer_connection).await;
-                        client.lock().await.peer_connection = Some(peer_connection);
-                    }
-                    "answer" => {
-                        if let Some(pc) = &client.lock().await.peer_connection {
-                            pc.process_answer(sdp_message.to_string()).await;
-                        }
-                        debug!("Got sdp answer from client {}", client_id);
-                    }
-                    _ => {
-                        debug!(
-                            "Unkown SDP message type {:?}",
-                            sdp_message_type.as_str().unwrap()
-                        );
-                    }
-                }
-            }
-            None => {
-                debug!("SDP message doesn't have a type entry: {:?}", sdp_message);
+        Err(_) => {
+            if msg.is_binary() {
+                let error = SignalError::new(
+                    ErrorCode::InvalidMessage,
+                    "binary messages are not supported",
+                );
+                send_message(&client.lock().await.sender, &error.to_message(None));
             }
+            return;
+        }
+    };
+
+    // The id is extracted separately so that errors for messages which fail to parse can still
+    // be correlated by the client.
+    let envelope = match parse_message(message_str) {
+        Ok(envelope) => envelope,
+        Err((message_id, error)) => {
+            warn!("Invalid message from client {}: {}", client_id, error);
+            send_message(&client.lock().await.sender, &error.to_message(message_id));
+            return;
         }
+    };
+
+    if let Err(error) = handle_message(client_id, &client, envelope.message, groups, options).await
+    {
+        warn!(
+            "Error handling message from client {}: {}",
+            client_id, error
+        );
+        send_message(&client.lock().await.sender, &error.to_message(envelope.id));
     }
+}
 
-    if let Some(ice_candidate) = message.get("ice") {
-        info!("Got ice candidate from client {}", client_id);
-        let client = clients.get(client_id).unwrap().lock().await;
-        match &client.peer_connection {
-            Some(pc) => {
-                info!("Processing new ice cadidate for client {:?}", client_id);
-                pc.process_ice_candidate(ice_candidate.to_string()).await;
+fn parse_message(message_str: &str) -> Result<ClientEnvelope, (Option<String>, SignalError)> {
+    let value: Value = serde_json::from_str(message_str).map_err(|err| {
+        (
+            None,
+            SignalError::new(ErrorCode::InvalidMessage, format!("invalid JSON: {}", err)),
+        )
+    })?;
+    let message_id = value.get("id").and_then(Value::as_str).map(str::to_owned);
+    match serde_json::from_value::<ClientEnvelope>(value) {
+        Ok(envelope) => Ok(envelope),
+        Err(err) => Err((
+            message_id,
+            SignalError::new(ErrorCode::InvalidMessage, err.to_string()),
+        )),
+    }
+}
+
+async fn handle_message(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    message: ClientMessage,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    if client.lock().await.protocol_version.is_none() {
+        return match message {
+            ClientMessage::Hello {
+                protocol_version,
+                token,
+            } => hello(client_id, client, protocol_version, token, options).await,
+            _ => Err(SignalError::new(
+                ErrorCode::HandshakeRequired,
+                "hello is required before any other message",
+            )),
+        };
+    }
+
+    match message {
+        ClientMessage::Hello { .. } => {
+            debug!("Ignoring repeated hello from client {}", client_id);
+        }
+        ClientMessage::Join { group, role } => {
+            join(client_id, client, group, role, groups, options).await?
+        }
+        ClientMessage::Leave => {
+            if let Some(group) = leave(client_id, client, groups, options).await {
+                send_message(&client.lock().await.sender, &ServerMessage::Left { group });
+            }
+        }
+        ClientMessage::Offer { sdp } => offer(client_id, client, sdp, groups, options).await?,
+        ClientMessage::Control { command } => control(client_id, client, command, groups).await?,
+        ClientMessage::SelectLayer { track_id, rid } => {
+            let client = client.lock().await;
+            let pc = client
+                .peer_connection
+                .as_ref()
+                .ok_or_else(no_peer_connection)?;
+            pc.select_layer(&track_id, rid).await?;
+        }
+        ClientMessage::Pause { track_id } => pause(client, &track_id, true).await?,
+        ClientMessage::Resume { track_id } => pause(client, &track_id, false).await?,
+        ClientMessage::Mute { kind } => mute(client, &kind, true, groups).await?,
+        ClientMessage::Unmute { kind } => mute(client, &kind, false, groups).await?,
+        ClientMessage::StartRecording => record(client_id, client, true, groups, options).await?,
+        ClientMessage::StopRecording => record(client_id, client, false, groups, options).await?,
+        ClientMessage::LastN { limit, pinned } => {
+            let mut client = client.lock().await;
+            client.last_n = LastN { limit, pinned };
+            if let Some(pc) = &client.peer_connection {
+                pc.set_last_n(client.last_n.clone()).await;
             }
-            None => {
-                error!("couldn't add ice candidate to client as it didn't have a webrtc connection")
+        }
+        ClientMessage::Answer { sdp } => {
+            debug!("Got sdp answer from client {}", client_id);
+            let client = client.lock().await;
+            let pc = client
+                .peer_connection
+                .as_ref()
+                .ok_or_else(no_peer_connection)?;
+            pc.process_answer(sdp)
+                .await
+                .map_err(|err| SignalError::new(ErrorCode::NegotiationFailed, err.to_string()))?;
+        }
+        ClientMessage::Ice { ice } => {
+            info!("Got ice candidate from client {}", client_id);
+            let mut client = client.lock().await;
+            match &client.peer_connection {
+                Some(pc) => pc.process_ice_candidate(ice).await.map_err(|err| {
+                    SignalError::new(ErrorCode::InvalidCandidate, err.to_string())
+                })?,
+                // Trickled ahead of the offer, added once the offer created the connection.
+                None if client.pending_candidates.len() < MAX_PENDING_CANDIDATES => {
+                    client.pending_candidates.push(ice)
+                }
+                None => {
+                    return Err(SignalError::new(
+                        ErrorCode::InvalidCandidate,
+                        format!(
+                            "more than {} candidates before the offer",
+                            MAX_PENDING_CANDIDATES
+                        ),
+                    ))
+                }
             }
         }
     }
+    Ok(())
+}
+
+fn offer_collision() -> SignalError {
+    SignalError::new(
+        ErrorCode::OfferCollision,
+        "your offer collided with an offer of the server, roll it back and answer ours",
+    )
+}
+
+fn no_peer_connection() -> SignalError {
+    SignalError::new(
+        ErrorCode::NoPeerConnection,
+        "no webrtc connection exists yet, send an offer first",
+    )
+}
+
+async fn hello(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    protocol_version: u32,
+    token: Option<String>,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    let mut client = client.lock().await;
+    if let Some(authenticator) = &options.authenticator {
+        if client.claims.is_none() {
+            let token = token
+                .ok_or_else(|| SignalError::new(ErrorCode::Unauthorized, "a token is required"))?;
+            let claims = authenticator.verify(&token).map_err(|err| {
+                SignalError::new(ErrorCode::Unauthorized, format!("invalid token: {}", err))
+            })?;
+            client.claims = Some(claims);
+        }
+        if let Some(claims) = &client.claims {
+            info!("Client {} authenticated as {}", client_id, claims.sub);
+        }
+    }
+    let version = negotiate_version(protocol_version).ok_or_else(|| {
+        SignalError::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "protocol version {} is not supported, expected {}..={}",
+                protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        )
+    })?;
+    info!("Client {} speaks protocol version {}", client_id, version);
+    client.protocol_version = Some(version);
+    send_message(
+        &client.sender,
+        &ServerMessage::Welcome {
+            protocol_version: version,
+            client_id: client_id.to_owned(),
+        },
+    );
+    Ok(())
+}
+
+async fn join(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    group_id: String,
+    role: Option<Role>,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    let role = if options.authenticator.is_some() {
+        authorize_join(&client.lock().await.claims, &group_id, role)?
+    } else {
+        role.unwrap_or_default()
+    };
+    let (current_group, current_role) = {
+        let client = client.lock().await;
+        (client.group.clone(), client.role)
+    };
+    if current_group.as_ref() != Some(&group_id) {
+        check_capacity(&group_id, groups, options).await?;
+    }
+    if current_group.as_ref() != Some(&group_id) || current_role != role {
+        if let Some(previous) = leave(client_id, client, groups, options).await {
+            send_message(
+                &client.lock().await.sender,
+                &ServerMessage::Left { group: previous },
+            );
+        }
+        let group = group_entry(groups, &group_id).await;
+        // FIXME: cloning the mutex with client is potentially dangerous ad &Clients and
+        // Groups[group] contains the client.
+        {
+            let mut client = client.lock().await;
+            client.group = Some(group_id.clone());
+            client.role = role;
+        }
+        group.lock().await.subscribe(client.clone()).await;
+        info!(
+            "Client {} joined group {} as {:?}",
+            client_id, group_id, role
+        );
+    }
+    let recording = match groups.lock().await.get(&group_id) {
+        Some(group) => group.lock().await.recorder.is_some(),
+        None => false,
+    };
+    send_message(
+        &client.lock().await.sender,
+        &ServerMessage::Joined {
+            group: group_id,
+            role,
+            recording,
+        },
+    );
+    Ok(())
+}
+
+/// The group `group_id`, created if it doesn't exist yet.
+pub async fn group_entry(groups: &Groups, group_id: &str) -> Arc<Mutex<Group>> {
+    groups
+        .lock()
+        .await
+        .entry(group_id.to_owned())
+        .or_insert_with(|| {
+            let group = Arc::new(Mutex::new(Group::new()));
+            speaker::spawn_detection(Arc::downgrade(&group));
+            group
+        })
+        .clone()
+}
+
+pub async fn check_capacity(
+    group_id: &str,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    let max_clients = match options.max_group_clients {
+        Some(max_clients) => max_clients,
+        None => return Ok(()),
+    };
+    let group = groups.lock().await.get(group_id).cloned();
+    if let Some(group) = group {
+        if group.lock().await.len().await >= max_clients {
+            return Err(SignalError::new(
+                ErrorCode::GroupFull,
+                format!("group {} already has {} clients", group_id, max_clients),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the client may join `group_id` and resolves the role it plays there.
+pub fn authorize_join(
+    claims: &Option<Claims>,
+    group_id: &str,
+    requested_role: Option<Role>,
+) -> Result<Role, SignalError> {
+    let claims = claims
+        .as_ref()
+        .ok_or_else(|| SignalError::new(ErrorCode::Unauthorized, "not authenticated"))?;
+    if claims.is_expired() {
+        return Err(SignalError::new(ErrorCode::Unauthorized, "token expired"));
+    }
+    if !claims.allows_group(group_id) {
+        return Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("{} may not join group {}", claims.sub, group_id),
+        ));
+    }
+    match (claims.role, requested_role) {
+        (Some(granted), Some(requested)) if granted != requested => Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("{} may only join as {:?}", claims.sub, granted),
+        )),
+        (Some(granted), _) => Ok(granted),
+        // Operators command robots, the token has to say so.
+        (None, Some(Role::Operator)) => Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "{} has no role claim allowing to join as operator",
+                claims.sub
+            ),
+        )),
+        (None, requested) => Ok(requested.unwrap_or_default()),
+    }
+}
+
+async fn control(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    command: Value,
+    groups: &Groups,
+) -> Result<(), SignalError> {
+    let (group_id, role) = {
+        let client = client.lock().await;
+        (client.group.clone(), client.role)
+    };
+    if !role.can_control() {
+        return Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("{:?} clients may not send control commands", role),
+        ));
+    }
+    let group = match group_id {
+        Some(group_id) => groups.lock().await.get(&group_id).cloned(),
+        None => None,
+    }
+    .ok_or_else(|| SignalError::new(ErrorCode::NotInGroup, "join a group first"))?;
+    let message = ServerMessage::Control {
+        from: client_id.to_owned(),
+        command,
+    };
+    group
+        .lock()
+        .await
+        .broadcast_to_role(Role::Robot, &message)
+        .await;
+    Ok(())
+}
+
+/// Starts or stops recording the group of the client.
+async fn record(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    recording: bool,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    let (group_id, role) = {
+        let client = client.lock().await;
+        (client.group.clone(), client.role)
+    };
+    if !role.can_control() {
+        return Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("{:?} clients may not record the group", role),
+        ));
+    }
+    let directory = options.recording_directory.as_ref().ok_or_else(|| {
+        SignalError::new(
+            ErrorCode::RecordingDisabled,
+            "recording is disabled on this server",
+        )
+    })?;
+    let group_id =
+        group_id.ok_or_else(|| SignalError::new(ErrorCode::NotInGroup, "join a group first"))?;
+    let group = groups
+        .lock()
+        .await
+        .get(&group_id)
+        .cloned()
+        .ok_or_else(|| SignalError::new(ErrorCode::NotInGroup, "join a group first"))?;
+    let mut group = group.lock().await;
+    let changed = if recording {
+        group.start_recording(&group_id, directory).await
+    } else {
+        group.stop_recording().await
+    };
+    if changed {
+        info!(
+            "Client {} {} recording group {}",
+            client_id,
+            if recording { "started" } else { "stopped" },
+            group_id
+        );
+    }
+    Ok(())
+}
+
+async fn pause(
+    client: &Arc<Mutex<Client>>,
+    track_id: &str,
+    paused: bool,
+) -> Result<(), SignalError> {
+    let client = client.lock().await;
+    let pc = client
+        .peer_connection
+        .as_ref()
+        .ok_or_else(no_peer_connection)?;
+    pc.set_paused(track_id, paused).await
+}
+
+/// Mutes or unmutes the tracks of `kind` published by the client and tells the group about it.
+async fn mute(
+    client: &Arc<Mutex<Client>>,
+    kind: &str,
+    muted: bool,
+    groups: &Groups,
+) -> Result<(), SignalError> {
+    let (group_id, tracks) = {
+        let client = client.lock().await;
+        let pc = client
+            .peer_connection
+            .as_ref()
+            .ok_or_else(no_peer_connection)?;
+        let tracks = pc.published_tracks(RTPCodecType::from(kind)).await;
+        (client.group.clone(), tracks)
+    };
+    if tracks.is_empty() {
+        return Err(SignalError::new(
+            ErrorCode::UnknownTrack,
+            format!("you don't publish any {} track", kind),
+        ));
+    }
+    let group = match group_id {
+        Some(group_id) => groups.lock().await.get(&group_id).cloned(),
+        None => None,
+    };
+    for track in tracks {
+        if !track.set_muted(muted) {
+            continue;
+        }
+        debug!("Track {} muted: {}", track.id(), muted);
+        if let Some(group) = &group {
+            let message = ServerMessage::TrackMuted {
+                track_id: track.id().to_owned(),
+                stream_id: track.publisher_id().to_owned(),
+                muted,
+            };
+            group.lock().await.broadcast(&message).await;
+        }
+    }
+    Ok(())
+}
+
+/// Rejects offers lacking the codecs the group's members use, and pins the codecs the offer
+/// prefers out of `supported` for the kinds it is the first to send. Both happen under the lock
+/// of the group's codecs, concurrent offers can't pin different ones.
+pub async fn check_offer_codecs(
+    group: &Arc<Mutex<Group>>,
+    sdp: &str,
+    supported: &[RTCRtpCodecCapability],
+) -> Result<(), SignalError> {
+    let description = RTCSessionDescription::offer(sdp.to_owned())
+        .and_then(|description| description.unmarshal())
+        .map_err(|err| SignalError::new(ErrorCode::NegotiationFailed, err.to_string()))?;
+    let codecs = group.lock().await.codecs.clone();
+    let mut codecs = codecs.lock().await;
+    codecs::check_offer_codecs(&description, &codecs)
+        .map_err(|err| SignalError::new(ErrorCode::UnsupportedCodec, err.to_string()))?;
+    codecs::pin_offer_codecs(&description, &mut codecs, supported);
+    Ok(())
+}
+
+/// Rejects offers sending media the client's role may not publish.
+fn check_published_media(role: Role, sdp: &str) -> Result<(), SignalError> {
+    if role.can_publish() {
+        return Ok(());
+    }
+    let kinds = published_kinds(sdp)
+        .map_err(|err| SignalError::new(ErrorCode::NegotiationFailed, err.to_string()))?;
+    if let Some(kind) = kinds.first() {
+        return Err(SignalError::new(
+            ErrorCode::Forbidden,
+            format!("{:?} clients may not publish {}", role, kind),
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the client from its group, closing its peer connection and withdrawing its tracks
+/// from the remaining members. Returns the id of the group that was left.
+pub async fn leave(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Option<String> {
+    let (group_id, peer_connection) = {
+        let mut client = client.lock().await;
+        client.pending_candidates.clear();
+        (client.group.take()?, client.peer_connection.take())
+    };
+    info!("Client {} left group {}", client_id, group_id);
+
+    let group = groups.lock().await.get(&group_id).cloned();
+    if let Some(group) = group {
+        let remaining = group.lock().await.unsubscribe(client).await;
+        if let Some(pc) = peer_connection {
+            // Taken out of the map so the forwarding tasks don't withdraw them a second time.
+            let tracks: Vec<_> = pc
+                .get_tracks()
+                .lock()
+                .await
+                .drain()
+                .map(|(_, track)| track)
+                .collect();
+            group.lock().await.remove_tracks(&tracks).await;
+            pc.close().await;
+        } else {
+            // The offer of a client that never published may have pinned codecs.
+            group.lock().await.release_codecs().await;
+        }
+        if remaining == 0 {
+            schedule_teardown(group_id.clone(), groups.clone(), options.group_grace_period);
+        }
+    } else if let Some(pc) = peer_connection {
+        pc.close().await;
+    }
+    Some(group_id)
+}
+
+/// Drops the group once the grace period expired, unless somebody joined it in the meantime.
+fn schedule_teardown(group_id: String, groups: Groups, grace_period: Duration) {
+    debug!(
+        "Group {} is empty, tearing it down in {:?}",
+        group_id, grace_period
+    );
+    tokio::spawn(async move {
+        tokio::time::sleep(grace_period).await;
+        let mut groups = groups.lock().await;
+        let empty = match groups.get(&group_id) {
+            Some(group) => group.lock().await.is_empty().await,
+            None => false,
+        };
+        if empty {
+            groups.remove(&group_id);
+            info!("Group {} torn down", group_id);
+        }
+    });
+}
+
+async fn offer(
+    client_id: &str,
+    client: &Arc<Mutex<Client>>,
+    sdp: String,
+    groups: &Groups,
+    options: &ServerOptions,
+) -> Result<(), SignalError> {
+    info!("Got offer from client {}", client_id);
+    let (role, group_id, sender, last_n) = {
+        let client = client.lock().await;
+        (
+            client.role,
+            client.group.clone(),
+            client.sender.clone(),
+            client.last_n.clone(),
+        )
+    };
+    check_published_media(role, &sdp)?;
+    let group_id = group_id.ok_or_else(|| {
+        SignalError::new(
+            ErrorCode::NotInGroup,
+            "join a group before sending an offer",
+        )
+    })?;
+    let group = groups.lock().await.get(&group_id).cloned().ok_or_else(|| {
+        SignalError::new(
+            ErrorCode::NotInGroup,
+            format!("group {} does not exist", group_id),
+        )
+    })?;
+    check_offer_codecs(&group, &sdp, &options.codecs).await?;
+
+    // Renegotiation of an established session, initiated by the client. The connection is
+    // cloned out so that its callbacks can lock the client meanwhile.
+    let pc = client.lock().await.peer_connection.clone();
+    if let Some(pc) = pc {
+        let answered = pc
+            .process_offer(sdp)
+            .await
+            .map_err(|err| SignalError::new(ErrorCode::NegotiationFailed, err.to_string()))?;
+        if !answered {
+            debug!("Offer from client {} lost the glare", client_id);
+            return Err(offer_collision());
+        }
+        return Ok(());
+    }
+
+    let mut peer_connection = match WebRTCConnection::new(
+        sender,
+        group.clone(),
+        role,
+        &options.api,
+        options.ice_servers.clone(),
+        last_n,
+        options.rtp_dump_directory.as_deref(),
+    )
+    .await
+    {
+        Ok(conn) => {
+            debug!("Successfull WebRTCConnection created {:?}", conn);
+            conn
+        }
+        Err(err) => {
+            return Err(SignalError::new(
+                ErrorCode::PeerConnectionFailed,
+                err.to_string(),
+            ));
+        }
+    };
+    peer_connection.set_participant(client.lock().await.identity());
+    peer_connection.setup_callbacks().await;
+    // Kept by the client until the offer is applied, a rejected offer leaves them to the next.
+    let pending_candidates = client.lock().await.pending_candidates.clone();
+    for candidate in pending_candidates {
+        // Buffered by the connection until the offer is applied below.
+        if let Err(err) = peer_connection.process_ice_candidate(candidate).await {
+            warn!("Dropping ice candidate of client {}: {}", client_id, err);
+        }
+    }
+    // The transceivers of a fresh connection may have made it send an offer of its own already,
+    // the connection is kept for the client to answer that one instead.
+    let answered = match peer_connection.process_offer(sdp).await {
+        Ok(answered) => answered,
+        Err(err) => {
+            peer_connection.close().await;
+            return Err(SignalError::new(
+                ErrorCode::NegotiationFailed,
+                err.to_string(),
+            ));
+        }
+    };
+    group.lock().await.add_tracks(&mut peer_connection).await;
+    {
+        let mut client = client.lock().await;
+        client.pending_candidates.clear();
+        client.peer_connection = Some(peer_connection);
+    }
+    if !answered {
+        debug!("First offer from client {} lost the glare", client_id);
+        return Err(offer_collision());
+    }
+    Ok(())
 }
@@ -4,19 +4,20 @@ var peerConnection;
 var uuid;
 var serverConnection;
 var videos;
+// Perfect negotiation: the server is impolite, so this client rolls back its own offer on glare.
+var makingOffer = false;
 
-var peerConnectionConfig = {
-  'iceServers': [
-    {'urls': 'stun:stun.stunprotocol.org:3478'},
-    {'urls': 'stun:stun.l.google.com:19302'},
-  ]
-};
+// Filled in from the server's config message
+var peerConnectionConfig = {'iceServers': []};
 const GROUP = 'testgroup';
+const PROTOCOL_VERSION = 1;
+var messageId = 0;
+var resumeToken = null;
+var resumed = false;
 
-// msg is a dict
+// msg is a dict with a 'type' entry, see signal_server/src/protocol.rs
 function send(serverConnection, msg) {
-  msg['headers'] = {}
-  msg['headers']['stream-group'] = GROUP; // group header
+  msg['id'] = String(messageId++);
   serverConnection.send(JSON.stringify(msg));
 }
 
@@ -27,8 +28,7 @@ function pageReady() {
   videos = document.querySelector('.videos');
   console.log(videos);
 
-  serverConnection = new WebSocket('ws://localhost:9999/signal');
-  serverConnection.onmessage = gotMessageFromServer;
+  connect();
 
   var constraints = {
     video: true,
@@ -42,6 +42,22 @@ function pageReady() {
   }
 }
 
+function connect() {
+  var url = 'ws://localhost:9999/signal?protocol_version=' + PROTOCOL_VERSION;
+  if(resumeToken) url += '&resume=' + resumeToken;
+  serverConnection = new WebSocket(url);
+  serverConnection.onmessage = gotMessageFromServer;
+  serverConnection.onopen = function() {
+    // Open the page with ?token=<jwt> when the server requires authentication
+    var token = new URLSearchParams(window.location.search).get('token');
+    send(serverConnection, {'type': 'hello', 'protocol_version': PROTOCOL_VERSION, 'token': token});
+  };
+  // Take the session over again after network changes, the peer connection is kept
+  serverConnection.onclose = function() {
+    setTimeout(connect, 1000);
+  };
+}
+
 function getUserMediaSuccess(stream) {
   localStream = stream;
   localVideo.srcObject = stream;
@@ -54,9 +70,31 @@ function start(isCaller) {
 
   peerConnection.onnegotiationneeded = async () => {
     console.log('negotiation needed');
-    peerConnection.createOffer().then(createdDescription).catch(errorHandler);
+    try {
+      makingOffer = true;
+      await peerConnection.setLocalDescription();
+      var local = peerConnection.localDescription;
+      send(serverConnection, {'type': local.type, 'sdp': local.sdp});
+    } catch(error) {
+      errorHandler(error);
+    } finally {
+      makingOffer = false;
+    }
+  }
+  if(new URLSearchParams(window.location.search).has('simulcast')) {
+    // Layers the server picks from for each subscriber, see selectLayer()
+    localStream.getAudioTracks().forEach(track => peerConnection.addTrack(track, localStream));
+    localStream.getVideoTracks().forEach(track => peerConnection.addTransceiver(track, {
+      streams: [localStream],
+      sendEncodings: [
+        {rid: 'q', scaleResolutionDownBy: 4, maxBitrate: 150000},
+        {rid: 'h', scaleResolutionDownBy: 2, maxBitrate: 500000},
+        {rid: 'f', maxBitrate: 1500000},
+      ],
+    }));
+  } else {
+    peerConnection.addStream(localStream);
   }
-  peerConnection.addStream(localStream);
 
   // if(isCaller) {
     // peerConnection.createOffer().then(createdDescription).catch(errorHandler);
@@ -64,29 +102,73 @@ function start(isCaller) {
 }
 
 function gotMessageFromServer(message) {
-  if(!peerConnection) start(false);
-
   var signal = JSON.parse(message.data);
 
-  // Ignore messages from ourself
-  if(signal.uuid == uuid) return;
-
   console.log("Got message from server ", signal);
-  if(signal.sdp) {
-    peerConnection.setRemoteDescription(new RTCSessionDescription(signal.sdp)).then(function() {
-      // Only create answers in response to offers
-      if(signal.sdp.type == 'offer') {
-        peerConnection.createAnswer().then(createdDescription).catch(errorHandler);
+  switch(signal.type) {
+    case 'config':
+      peerConnectionConfig = {'iceServers': signal.ice_servers};
+      resumeToken = signal.resume_token;
+      resumed = signal.resumed;
+      break;
+    case 'welcome':
+      if(resumed) break; // still in the group
+      // ?role=robot|operator|viewer, operator when omitted since the server defaults to viewer
+      var role = new URLSearchParams(window.location.search).get('role') || 'operator';
+      send(serverConnection, {'type': 'join', 'group': GROUP, 'role': role});
+      break;
+    case 'offer':
+    case 'answer':
+      if(!peerConnection) start(false);
+      // Setting a remote offer while we have a local one pending rolls ours back implicitly.
+      if(signal.type == 'offer' && (makingOffer || peerConnection.signalingState != 'stable')) {
+        console.log('offer collision, rolling back our offer');
       }
-    }).catch(errorHandler);
-  } else if(signal.ice) {
-    peerConnection.addIceCandidate(new RTCIceCandidate(signal.ice)).catch(errorHandler);
+      peerConnection.setRemoteDescription({'type': signal.type, 'sdp': signal.sdp}).then(function() {
+        // Only create answers in response to offers
+        if(signal.type == 'offer') {
+          peerConnection.createAnswer().then(createdDescription).catch(errorHandler);
+        }
+      }).catch(errorHandler);
+      break;
+    case 'ice':
+      if(!peerConnection) start(false);
+      peerConnection.addIceCandidate(new RTCIceCandidate(signal.ice)).catch(errorHandler);
+      break;
+    case 'error':
+      console.error('Server error', signal);
+      break;
   }
 }
 
+// Call from the console with a track id of a track-added message, omit rid to let the server pick
+function selectLayer(trackId, rid) {
+  send(serverConnection, {'type': 'select-layer', 'track_id': trackId, 'rid': rid});
+}
+
+// Stops and restarts the forwarding of a track to us, e.g. while its view is minimized
+function pauseTrack(trackId, paused) {
+  send(serverConnection, {'type': paused ? 'pause' : 'resume', 'track_id': trackId});
+}
+
+// Stops and restarts the forwarding of our own 'audio' or 'video' track to the group
+function muteTrack(kind, muted) {
+  send(serverConnection, {'type': muted ? 'mute' : 'unmute', 'kind': kind});
+}
+
+// Receives the video of `limit` publishers at most, those of the `pinned` stream ids first
+function setLastN(limit, pinned) {
+  send(serverConnection, {'type': 'last-n', 'limit': limit, 'pinned': pinned || []});
+}
+
+// Starts or stops recording the group, operators only
+function setRecording(recording) {
+  send(serverConnection, {'type': recording ? 'start-recording' : 'stop-recording'});
+}
+
 function gotIceCandidate(event) {
   if(event.candidate != null) {
-    send(serverConnection, {'ice': event.candidate, 'uuid': uuid});
+    send(serverConnection, {'type': 'ice', 'ice': event.candidate.toJSON()});
   }
 }
 
@@ -94,7 +176,8 @@ function createdDescription(description) {
   console.log('got description', description);
 
   peerConnection.setLocalDescription(description).then(function() {
-    send(serverConnection, {'sdp': peerConnection.localDescription, 'uuid': uuid});
+    var local = peerConnection.localDescription;
+    send(serverConnection, {'type': local.type, 'sdp': local.sdp});
   }).catch(errorHandler);
 }
 